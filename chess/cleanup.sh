@@ -2,4 +2,4 @@
 
 rm lichess_db_puzzle.csv.zst
 rm lichess_db_puzzle.csv
-rm -fr output fenpuzzles
+rm -fr output fenpuzzles sections errors.csv
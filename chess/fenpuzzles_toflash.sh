@@ -1,80 +1,700 @@
 #! /bin/bash
 
 # Generate flash file.  A puzzle is a series of
-# text files, 75 chars each.  Each text file is aligned
-# to 96 bytes (ROW_SIZE)
+# pages, 75 chars each, read from the pages.csv written by genfenpuzzles.sh
+# (or from one text file per page).  Each page is aligned
+# to 96 bytes (ROW_SIZE, --row-size for firmware with other page sizes)
+#
+# Puzzles are taken from the directories given as arguments (fenpuzzles by
+# default).  Each directory is a section of the rom, sections are packed one
+# after the other and their boundaries are recorded in the config sector,
+# with a CRC32 of all the pages to detect corrupted transfers and when and
+# from which filters (the filters.txt of the directories) it was built.
+#
+# A directory with a text.txt file instead is a section of text pages, one
+# per line, in rows of --text-row-size bytes.  Every content type takes one
+# of the four type slots of the config sector, its sections must be given
+# one after the other as the pages of a type are stored together.
+#
+# NOTE: This script requires GNU getopt, see genfenpuzzles.sh
+GETOPT=/usr/bin/getopt
+[ -f /usr/local/opt/gnu-getopt/bin/getopt ] && GETOPT=/usr/local/opt/gnu-getopt/bin/getopt
 
-ROW_SIZE=96
-MAX_MOVES_PER_PUZZLE=4
-MAX_PUZZLE_SIZE=$((ROW_SIZE * MAX_MOVES_PER_PUZZLE))
-FLASH_SIZE=16777216
-CONFIG_SECTOR_SIZE=0x1000
-CONFIG_STRUCT_SIZE=36
+TEMP=$(${GETOPT} -o ho: --long help,log-format:,output:,report:,fill-tail,titles,format:,flash-address:,order:,max-bytes:,max-percent:,flash-size:,row-size:,text-row-size:,append:,format-version:,index,packed-board,rle,fill-byte:,sector-align,split-size:,progress-sectors:,font-size:,content-type:,endian:,row-template:,row-fen:,row-meta,id-field,seed:,build-time:,page-checksum \
+              -n 'fenpuzzles_toflash.sh' -- "$@")
+
+if [ $? != 0 ] ; then echo "Terminating..." >&2 ; exit 1 ; fi
+
+# Note the quotes around '$TEMP': they are essential!
+eval set -- "$TEMP"
 
+function print_usage() {
+    echo "$0 <options> [SECTION_DIR...]"
+    echo "-o, --output: path of the rom, its directory is created if needed (default lightnote.rom)"
+    echo "--log-format: text (default) or json for one JSON object per message and line, e.g. for CI logs"
+    echo "--format: also write the rom as uf2, next to it, to flash it by dropping it on the RP2040 bootloader drive, or as srec (Motorola S-records) for universal programmers"
+    echo "--flash-address: address the rom is flashed at in the uf2 or srec file (default 0x10000000, the start of the RP2040 flash, for uf2 and 0 for srec)"
+    echo "--report: write a JSON report of the build to this file, with the puzzles, their counts by theme and rating, the pages used, the free bytes and why genfenpuzzles.sh skipped puzzles"
+    echo "--order: order of the puzzles in the rom, one of id (default), rating-asc, rating-desc, random or curriculum"
+    echo "--seed: seed of the random order, the same seed gives the same rom"
+    echo "--fill-tail when the next puzzle does not fit anymore, go on with the shorter ones after it that still fit rather than stop, out of the --order for the last puzzles"
+    echo "--titles start the rom with a title page for every section, its name (title.txt of the directory, or the directory name) and its ratings, as text pages. Pages of a type are stored together, so they are all before the puzzles, as a table of contents"
+    echo "--max-bytes: maximum size of the puzzles in the rom, rest is left free (e.g. 8M)"
+    echo "--max-percent: maximum percentage of the flash used by the puzzles"
+    echo "--flash-size: size of the flash chip of the board, e.g. 8M or 32M (default 16M)"
+    echo "--row-size: bytes of every page in the rom, written to the config sector (default 96, 48 with --packed-board)"
+    echo "--text-row-size: bytes of every page of the text sections (default the --row-size, or 96)"
+    echo "--append: add the puzzles after those of this rom, skipping the ones already in it, the rom sections are kept"
+    echo "--page-checksum end every page with a checksum byte, needs a firmware reading config version 1"
+    echo "--index write an index of the puzzles after the pages, with their first page, number of pages and rating"
+    echo "--packed-board write the puzzle pages in binary, 4 bits a square, for twice as many puzzles. Needs a firmware reading config version 2"
+    echo "--rle run length encode every page, with a smaller --row-size for more puzzles (e.g. 80, or 44 with --packed-board). Every row keeps its fixed size, so it saves far less than compressing the whole rom would, and the puzzles with a page that does not fit the row even encoded are left out and counted. Needs a firmware reading config version 2"
+    echo "--fill-byte: value of the free flash after the puzzles (default 0xff, as erased flash, so flashing tools can skip it)"
+    echo "--sector-align start a puzzle on the next erase sector (4K) rather than have it span two, so it can be rewritten alone. The empty pages left are skipped by firmware reading config version 2"
+    echo "--split-size: also write the rom in banks of this size (e.g. 4M), as ROM.0, ROM.1..., for flashing tools with a file size limit. No puzzle spans two banks, the empty pages left are skipped as with --sector-align, that it implies"
+    echo "--font-size: font size written to the config sector for the firmware to render pages with (default 1)"
+    echo "--content-type: type id of the puzzle pages in the config sector, for firmware builds with another renderer (default 4, ChessPuzzle). romtool.py only reads the pages of type 4 as puzzles"
+    echo "--endian: byte order of the config sector fields, little (default) or big for firmware on a big endian MCU"
+    echo "--row-template: fields of the puzzle pages, in order, for firmware parsing other pages, from id, efen, move (from and to index), page, total and rating (default id,efen,move,page,total). Written to the config sector, needs a firmware reading config version 3. Pages the same in two puzzles without id or page are written for both, the firmware reads a puzzle as a run of pages"
+    echo "--row-fen: board of the puzzle pages, expanded in 64 chars (default) or compressed as in a FEN, about 30 chars, for more space for other fields. Sets the row template"
+    echo "--row-meta end every puzzle page with a difficulty byte and the bits of its themes (see THEME_BITS in romtool.py), for the device to show how hard a puzzle is and filter them by theme. Needs a firmware reading config version 3"
+    echo "--id-field start every puzzle page with the puzzle id in 8 bytes (see PUZZLE_ID_SIZE in romtool.py), for the device to show lichess.org/training/<id> without parsing the page. Needs a firmware reading config version 3"
+    echo "--progress-sectors: erase sectors (4K) kept before the config sector for the firmware to save which puzzles are solved (default 0)"
+    echo "--build-time: build time written to the config sector, in seconds since 1970 (default SOURCE_DATE_EPOCH, or now). The same puzzles, options and build time give the same rom"
+    echo "--format-version: config sector version of the firmware of the device, by default the oldest one with the features asked for"
+}
+
+LOG_FORMAT=text
 ROMFILE=lightnote.rom
+REPORT=""
+FORMAT=""
+FLASH_ADDRESS=""
+ORDER=id
+FILL_TAIL=false
+TITLES=false
+MAX_BYTES=""
+MAX_PERCENT=100
+PAGE_CHECKSUM=false
+FLASH_SIZE=16M
+ROW_SIZE=""
+TEXT_ROW_SIZE=""
+APPEND=""
+FORMAT_VERSION=""
+INDEX=false
+PACKED_BOARD=false
+RLE=false
+FILL_BYTE=0xff
+SECTOR_ALIGN=false
+SPLIT_SIZE=0
+PROGRESS_SECTORS=0
+FONT_SIZE=1
+CONTENT_TYPE=""
+ENDIAN=little
+ROW_TEMPLATE=""
+ROW_FEN=expanded
+ROW_META=false
+ID_FIELD=false
+SEED=""
+while true; do
+  case "$1" in
+    --log-format ) LOG_FORMAT="$2"; shift 2 ;;
+    -o | --output ) ROMFILE="$2"; shift 2 ;;
+    --report ) REPORT="$2"; shift 2 ;;
+    --format ) FORMAT="$2"; shift 2 ;;
+    --flash-address ) FLASH_ADDRESS="$2"; shift 2 ;;
+    --order ) ORDER="$2"; shift 2 ;;
+    --fill-tail ) FILL_TAIL=true; shift ;;
+    --titles ) TITLES=true; shift ;;
+    --max-bytes ) MAX_BYTES="$2"; shift 2 ;;
+    --max-percent ) MAX_PERCENT="$2"; shift 2 ;;
+    --flash-size ) FLASH_SIZE="$2"; shift 2 ;;
+    --row-size ) ROW_SIZE="$2"; shift 2 ;;
+    --text-row-size ) TEXT_ROW_SIZE="$2"; shift 2 ;;
+    --append ) APPEND="$2"; shift 2 ;;
+    --format-version ) FORMAT_VERSION="$2"; shift 2 ;;
+    --index ) INDEX=true; shift ;;
+    --packed-board ) PACKED_BOARD=true; shift ;;
+    --rle ) RLE=true; shift ;;
+    --fill-byte ) FILL_BYTE="$2"; shift 2 ;;
+    --sector-align ) SECTOR_ALIGN=true; shift ;;
+    --split-size ) SPLIT_SIZE="$2"; SECTOR_ALIGN=true; shift 2 ;;
+    --progress-sectors ) PROGRESS_SECTORS="$2"; shift 2 ;;
+    --font-size ) FONT_SIZE="$2"; shift 2 ;;
+    --content-type ) CONTENT_TYPE="$2"; shift 2 ;;
+    --endian ) ENDIAN="$2"; shift 2 ;;
+    --row-template ) ROW_TEMPLATE="$2"; shift 2 ;;
+    --row-fen ) ROW_FEN="$2"; shift 2 ;;
+    --row-meta ) ROW_META=true; shift ;;
+    --id-field ) ID_FIELD=true; shift ;;
+    --seed ) SEED="$2"; shift 2 ;;
+    --build-time ) export SOURCE_DATE_EPOCH="$2"; shift 2 ;;
+    --page-checksum ) PAGE_CHECKSUM=true; shift ;;
+    -h | --help ) print_usage; exit 0; ;;
+    -- ) shift; break ;;
+    * ) break ;;
+  esac
+done
+
+. ./functions.sh
+case ${LOG_FORMAT} in
+    text | json ) ;;
+    * ) log error "Unknown log format ${LOG_FORMAT}, text or json"; exit 1 ;;
+esac
+case ${FORMAT} in
+    "" | bin | uf2 | srec ) ;;
+    * ) log error "Unknown format ${FORMAT}"; exit 1 ;;
+esac
+case ${ENDIAN} in
+    little | big ) ;;
+    * ) log error "Unknown byte order ${ENDIAN}, little or big"; exit 1 ;;
+esac
+# a compressed board is the fen field of the row template, by default the
+# fields of DEFAULT_ROW_TEMPLATE in romtool.py
+case ${ROW_FEN} in
+    expanded ) ;;
+    compressed ) ROW_TEMPLATE=${ROW_TEMPLATE:-id,efen,move,page,total}; ROW_TEMPLATE=${ROW_TEMPLATE/efen/fen} ;;
+    * ) log error "Unknown row fen ${ROW_FEN}, expanded or compressed"; exit 1 ;;
+esac
+
+# see ConfigSector in romtool.py for the layout of the config sector
+CONFIG_SECTOR_SIZE=0x1000
+MAX_SECTIONS=16
+# features of the config sector flags, and the oldest config version a
+# firmware reading them has (see FLAGS in romtool.py).  Version 0 firmware
+# knows no flags
+LATEST_VERSION=3
+# pages end with a checksum byte, the sum of the other bytes
+FLAG_PAGE_CHECKSUM=1
+# an index of the puzzles follows the pages
+FLAG_INDEX=2
+# id: 16 bytes, first_page: u32, num_pages: u16, rating: u16
+INDEX_ENTRY_SIZE=24
+# puzzle pages are binary, see PACKED_ROW in romtool.py
+FLAG_PACKED_BOARD=4
+# pages are run length encoded, see rle_encode in romtool.py
+FLAG_RLE=8
+# puzzles do not span erase sectors, pages of zeros before them are padding
+FLAG_SECTOR_ALIGN=16
+# puzzle pages have the fields of the row template of the config sector
+FLAG_ROW_TEMPLATE=32
+# puzzle pages end with a difficulty and theme bits, see ROW_META in romtool.py
+FLAG_ROW_META=64
+# puzzle pages start with the puzzle id, see PUZZLE_ID_SIZE in romtool.py
+FLAG_PUZZLE_ID=128
+CONFIG_FLAGS=0
+CONFIG_VERSION=0
+[ ${PAGE_CHECKSUM} = "true" ] && { CONFIG_FLAGS=$((CONFIG_FLAGS | FLAG_PAGE_CHECKSUM)); CONFIG_VERSION=1; }
+[ ${INDEX} = "true" ] && { CONFIG_FLAGS=$((CONFIG_FLAGS | FLAG_INDEX)); CONFIG_VERSION=1; }
+[ ${PACKED_BOARD} = "true" ] && { CONFIG_FLAGS=$((CONFIG_FLAGS | FLAG_PACKED_BOARD)); CONFIG_VERSION=2; }
+[ ${RLE} = "true" ] && { CONFIG_FLAGS=$((CONFIG_FLAGS | FLAG_RLE)); CONFIG_VERSION=2; }
+[ ${SECTOR_ALIGN} = "true" ] && { CONFIG_FLAGS=$((CONFIG_FLAGS | FLAG_SECTOR_ALIGN)); CONFIG_VERSION=2; }
+[ -n "${ROW_TEMPLATE}" ] && { CONFIG_FLAGS=$((CONFIG_FLAGS | FLAG_ROW_TEMPLATE)); CONFIG_VERSION=3; }
+[ ${ROW_META} = "true" ] && { CONFIG_FLAGS=$((CONFIG_FLAGS | FLAG_ROW_META)); CONFIG_VERSION=3; }
+[ ${ID_FIELD} = "true" ] && { CONFIG_FLAGS=$((CONFIG_FLAGS | FLAG_PUZZLE_ID)); CONFIG_VERSION=3; }
+if [ -n "${FORMAT_VERSION}" ]
+then
+    [ ${FORMAT_VERSION} -gt ${LATEST_VERSION} ] && \
+        { log error "Unknown format version ${FORMAT_VERSION}, the latest is ${LATEST_VERSION}"; exit 1; }
+    [ ${FORMAT_VERSION} -lt ${CONFIG_VERSION} ] && \
+        { log error "The options given need format version ${CONFIG_VERSION}, not ${FORMAT_VERSION}"; exit 1; }
+    CONFIG_VERSION=${FORMAT_VERSION}
+fi
+TYPE_TEXT=1
+TYPE_CHESS_PUZZLE=4
+# type id and font size are u8 fields of the config sector
+[ -z "${CONTENT_TYPE}" ] && CONTENT_TYPE=${TYPE_CHESS_PUZZLE}
+[[ ${CONTENT_TYPE} =~ ^[0-9]+$ && ${CONTENT_TYPE} -ge 2 && ${CONTENT_TYPE} -le 255 ]] || \
+    { log error "Invalid content type ${CONTENT_TYPE}, from 2 to 255"; exit 1; }
+[[ ${FONT_SIZE} =~ ^[0-9]+$ && ${FONT_SIZE} -le 255 ]] || \
+    { log error "Invalid font size ${FONT_SIZE}, from 0 to 255"; exit 1; }
+[ -z "${TEXT_ROW_SIZE}" ] && TEXT_ROW_SIZE=${ROW_SIZE:-96}
+# a binary puzzle page is 37 bytes and the id
+[ -z "${ROW_SIZE}" ] && { ROW_SIZE=96; [ ${PACKED_BOARD} = "true" ] && ROW_SIZE=48; }
+# the template is for text pages, and must fit their rows
+if [ -n "${ROW_TEMPLATE}" ]
+then
+    [ ${PACKED_BOARD} = "true" ] && \
+        { log error "--row-template is for text pages, not with --packed-board"; exit 1; }
+    log_errors python -c "import sys
+from romtool import row_template_errors
+errors = row_template_errors('${ROW_TEMPLATE}', ${ROW_SIZE})
+if errors:
+    sys.exit('Invalid row template ${ROW_TEMPLATE}: ' + ', '.join(errors))" || exit 1
+fi
+
+# the config sector is the last sector of the flash
+FLASH_SIZE=$(to_bytes ${FLASH_SIZE})
+[[ $((FLASH_SIZE % CONFIG_SECTOR_SIZE)) -ne 0 || ${FLASH_SIZE} -le ${CONFIG_SECTOR_SIZE} ]] && \
+    { log error "Invalid flash size ${FLASH_SIZE}, must be a multiple of $((CONFIG_SECTOR_SIZE)) bytes"; exit 1; }
+SPLIT_SIZE=$(to_bytes ${SPLIT_SIZE})
+[ $((SPLIT_SIZE % CONFIG_SECTOR_SIZE)) -ne 0 ] && \
+    { log error "Invalid split size ${SPLIT_SIZE}, must be a multiple of $((CONFIG_SECTOR_SIZE)) bytes"; exit 1; }
+
+# the progress region, left erased, is the sectors before the config sector
+PROGRESS_SIZE=$((PROGRESS_SECTORS * CONFIG_SECTOR_SIZE))
+PROGRESS_OFFSET=$((FLASH_SIZE - CONFIG_SECTOR_SIZE - PROGRESS_SIZE))
+[ ${PROGRESS_OFFSET} -lt 0 ] && \
+    { log error "${PROGRESS_SECTORS} progress sectors do not fit a flash of ${FLASH_SIZE} bytes"; exit 1; }
+
+# space puzzles are allowed to take, anything else is left free for other
+# content to be added later
+DATA_SIZE=$(( PROGRESS_OFFSET * MAX_PERCENT / 100 ))
+if [ -n "${MAX_BYTES}" ]
+then
+    MAX_BYTES=$(to_bytes ${MAX_BYTES})
+    [ ${MAX_BYTES} -lt ${DATA_SIZE} ] && DATA_SIZE=${MAX_BYTES}
+fi
+
+# temporary files and directories, removed on exit
+TEMP_FILES=()
+trap 'rm -fr "${TEMP_FILES[@]}"' EXIT
+
+# new puzzles go in the space the rom to append to leaves, it may be the
+# rom being written so it is kept aside
+if [ -n "${APPEND}" ]
+then
+    [ -f ${APPEND} ] || { log error "${APPEND} not found"; exit 1; }
+    APPEND_ROM=$(mktemp)
+    TEMP_FILES+=(${APPEND_ROM})
+    cp ${APPEND} ${APPEND_ROM}
+    # the merge at the end needs the same layout, checked before anything is
+    # written so a mismatch never costs the rom
+    log_errors python -c $"import sys
+from romtool import MAGIC, Rom
+
+rom = Rom('${APPEND_ROM}')
+if rom.config.magic != MAGIC:
+    sys.exit('${APPEND}: not a rom, magic is 0x%08x' % rom.config.magic)
+if rom.config.flags != ${CONFIG_FLAGS}:
+    sys.exit('${APPEND}: config flags 0x%02x, the options given make 0x%02x' % (rom.config.flags, ${CONFIG_FLAGS}))
+if rom.config.row_template != '${ROW_TEMPLATE}':
+    sys.exit('${APPEND}: row template %s, the options given make %s' % (rom.config.row_template or 'none', '${ROW_TEMPLATE}' or 'none'))
+if rom.config.byte_order != '${ENDIAN}':
+    sys.exit('${APPEND}: %s endian, the options given make ${ENDIAN}' % rom.config.byte_order)
+for page_type, size in zip(rom.config.types, rom.config.sizes):
+    if page_type == ${CONTENT_TYPE} and size != ${ROW_SIZE}:
+        sys.exit('${APPEND}: pages of %d bytes, --row-size is ${ROW_SIZE}' % size)" || exit 1
+    APPEND_SIZE=$(python -c "from romtool import Rom; print(Rom('${APPEND_ROM}').data_size())")
+    DATA_SIZE=$((DATA_SIZE - APPEND_SIZE))
+    [ ${DATA_SIZE} -lt 0 ] && DATA_SIZE=0
+fi
+
+# with --append the new pages are built aside, the rom is only replaced
+# once they are merged with it
+OUTPUT_ROM=${ROMFILE}
+if [ -n "${APPEND}" ]
+then
+    ROMFILE=$(mktemp)
+    TEMP_FILES+=(${ROMFILE})
+fi
+mkdir -p $(dirname ${OUTPUT_ROM})
 rm -f ${ROMFILE}
-row_count=0
-puzzle_count=0
 
-echo "Generating rom file..."
+log info "Generating rom file..."
 # This is important or we get puzzles with same name and different case get
-# intermixed
-LANG=C
-for f in fenpuzzles/*.txt
+# intermixed, and the order of the puzzles would depend on the locale
+export LC_ALL=C
+# empty sections are fine
+shopt -s nullglob
+
+# Ramp up the rating in bands of CURRICULUM_BAND points and, within a band,
+# take one puzzle of each motif in turn so similar puzzles are not all
+# together.  Needs the index.csv written by genfenpuzzles.sh, run from within
+# the puzzles directory
+CURRICULUM_BAND=100
+function curriculum_order() {
+    python -c $"import collections
+
+# themes that say nothing about the tactic itself
+GENERIC = {'short', 'long', 'veryLong', 'oneMove', 'opening', 'middlegame',
+           'endgame', 'advantage', 'crushing', 'equality', 'mate', 'master',
+           'masterVsMaster', 'superGM'}
+
+def motif(themes):
+    return next((t for t in themes.split() if t not in GENERIC), 'other')
+
+puzzles = [line.rstrip('\n').split(',') for line in open('index.csv')]
+puzzles.sort(key=lambda p: (int(p[1]), p[0]))
+bands = collections.OrderedDict()
+for p in puzzles:
+    bands.setdefault(int(p[1]) // ${CURRICULUM_BAND}, []).append(p)
+for band in bands.values():
+    motifs = collections.OrderedDict()
+    for p in band:
+        motifs.setdefault(motif(p[2]), []).append(p)
+    while motifs:
+        for m in list(motifs):
+            print(motifs[m].pop(0)[0] + '-01.txt')
+            if not motifs[m]:
+                del motifs[m]"
+}
+
+# shuffle the puzzles, always in the same order for a --seed
+function random_order() {
+    if [ -z "${SEED}" ]
+    then
+        shuf
+    else
+        python -c $"import random, sys
+lines = sys.stdin.readlines()
+random.Random('${SEED}').shuffle(lines)
+sys.stdout.writelines(lines)"
+    fi
+}
+
+# files ending in -01.txt are beginnings of puzzles, named
+# puzzle-<id>-<rating>-<theme>-01.txt
+case ${ORDER} in
+    id ) SORT="sort" ;;
+    rating-asc ) SORT="sort -t- -k3,3n -k2,2" ;;
+    rating-desc ) SORT="sort -t- -k3,3nr -k2,2" ;;
+    random ) SORT="random_order" ;;
+    curriculum ) SORT="curriculum_order" ;;
+    * ) log error "Unknown order ${ORDER}"; exit 1 ;;
+esac
+SECTION_DIRS=("$@")
+[ ${#SECTION_DIRS[@]} -eq 0 ] && SECTION_DIRS=(fenpuzzles)
+# the title pages are a text section of their own, before the others
+if [ ${TITLES} = "true" ]
+then
+    TITLES_DIR=$(mktemp -d)
+    TEMP_FILES+=(${TITLES_DIR})
+    python -c $"import os, sys
+for number, directory in enumerate(sys.argv[1:], 1):
+    if os.path.exists(os.path.join(directory, 'text.txt')):
+        continue
+    title = os.path.basename(os.path.normpath(directory)).split('-', 1)[-1]
+    if os.path.exists(os.path.join(directory, 'title.txt')):
+        title = open(os.path.join(directory, 'title.txt')).readline().strip()
+    index = os.path.join(directory, 'index.csv')
+    ratings = [int(line.split(',')[1]) for line in open(index)] if os.path.exists(index) else []
+    if ratings:
+        title += ' - %d-%d' % (min(ratings), max(ratings))
+    print('%d. %s' % (number, title))" "${SECTION_DIRS[@]}" > ${TITLES_DIR}/text.txt
+    SECTION_DIRS=(${TITLES_DIR} "${SECTION_DIRS[@]}")
+fi
+[ ${#SECTION_DIRS[@]} -gt ${MAX_SECTIONS} ] && { log error "At most ${MAX_SECTIONS} sections are supported"; exit 1; }
+
+# first page of every puzzle in a directory, from pages.csv or, for puzzles
+# generated before it existed, from the page files.  Nothing for text
+# sections, their pages are all taken in order
+function list_puzzles() {
+    if [ -f $1/text.txt ]
+    then
+        return
+    elif [ -f $1/pages.csv ]
+    then
+        cut -d, -f1 $1/pages.csv | grep -- '-01$' | sed 's/$/.txt/'
+    else
+        (cd $1 && printf '%s\n' *-01.txt)
+    fi
+}
+
+# Write the pages of the puzzles to the rom, every page in a row of ROW_SIZE
+# bytes, then pad it up to the progress region and the config sector.  Reads the section directories
+# and their puzzles in order, each section as a "section <dir>" line followed
+# by the first page names.  With --index the index of the puzzles follows
+# the rows.  Prints the number of puzzles, the number of rows, the CRC32 of
+# the rows, their size in bytes, the offset of the index, the number of
+# puzzles left out by --rle, type:row_size:num_rows of every type and
+# first_row:num_rows of every section
+function write_rows() {
+    python -c $"import glob, os, sys, zlib
+from romtool import Rom, INDEX_ENTRY, index_offset, pack_row, rle_encode, align_rows, format_row, row_meta, PUZZLE_ID_SIZE, ROW_META
+
+# puzzles of the rom appended to are not added again
+known = set(Rom('${APPEND_ROM}').puzzles()) if '${APPEND}' else set()
+rom = open('${ROMFILE}', 'wb')
+pages = {}
+puzzles = rows = crc = size = 0
+# puzzles with a page too long for a row even run length encoded
+oversized = 0
+sections = []
+# [type, row size, rows] in the order of the type slots
+types = []
+full = False
+# id, first row, rows and rating of every puzzle, when there is an index
+index = []
+index_entry_size = INDEX_ENTRY.size if ${CONFIG_FLAGS} & ${FLAG_INDEX} else 0
+
+# every row of the rom is counted here, the config sector is made from the
+# counts of its types and sections
+def add_row(row):
+    global crc, rows, size
+    rom.write(row)
+    crc = zlib.crc32(row, crc)
+    rows += 1
+    size += len(row)
+    types[-1][2] += 1
+    sections[-1][1] += 1
+
+# a page as it is stored, before any run length encoding
+def encode_row(row, rating):
+    if ${CONFIG_FLAGS} & ${FLAG_PACKED_BOARD}:
+        return pack_row(row.decode().rstrip('\n'))
+    if '${ROW_TEMPLATE}':
+        return format_row(row.decode().rstrip('\n'), '${ROW_TEMPLATE}', rating).encode() + b'\n'
+    return row
+
+# the id of a puzzle, if any, starts its rows, the difficulty and themes end
+# them before the checksum
+def write(name, row, meta=b'', puzzle_id=b''):
+    if ${CONFIG_FLAGS} & ${FLAG_RLE}:
+        row = rle_encode(row)
+    row = puzzle_id + row
+    # the checksum byte takes the place of the last padding byte
+    if len(row) + len(meta) > row_size - bool(${CONFIG_FLAGS} & ${FLAG_PAGE_CHECKSUM}):
+        sys.exit('%s: page of %d bytes does not fit a row of %d' % (name, len(row) + len(meta), row_size))
+    row = row.ljust(row_size - len(meta) - bool(${CONFIG_FLAGS} & ${FLAG_PAGE_CHECKSUM}), b'\0') + meta
+    row = row.ljust(row_size, b'\0')
+    if ${CONFIG_FLAGS} & ${FLAG_PAGE_CHECKSUM}:
+        row = row[:-1] + bytes([sum(row[:-1]) & 0xff])
+    add_row(row)
+    if '${LOG_FORMAT}' == 'text':
+        sys.stderr.write('.')
+
+for line in sys.stdin:
+    line = line.rstrip('\n')
+    if line.startswith('section '):
+        directory = line.split(' ', 1)[1]
+        text = os.path.join(directory, 'text.txt')
+        page_type, row_size = (${TYPE_TEXT}, ${TEXT_ROW_SIZE}) if os.path.exists(text) else (${CONTENT_TYPE}, ${ROW_SIZE})
+        if not types or types[-1][0] != page_type:
+            if page_type in [t[0] for t in types]:
+                sys.exit('%s: sections of the same type must be given one after the other' % directory)
+            if len(types) == 4:
+                sys.exit('%s: at most 4 types of pages' % directory)
+            types.append([page_type, row_size, 0])
+        sections.append([rows, 0])
+        pages = {}
+        # themes of the puzzles, for their theme bits
+        themes = {}
+        if ${CONFIG_FLAGS} & ${FLAG_ROW_META} and os.path.exists(os.path.join(directory, 'index.csv')):
+            for entry in open(os.path.join(directory, 'index.csv')):
+                name, _, puzzle_themes = entry.rstrip('\n').split(',', 2)
+                themes[name] = puzzle_themes.split()
+        full = False
+        if page_type == ${TYPE_TEXT}:
+            for row in open(text, 'rb'):
+                if size + row_size > ${DATA_SIZE}:
+                    break
+                write(text, row)
+        elif os.path.exists(os.path.join(directory, 'pages.csv')):
+            for page in open(os.path.join(directory, 'pages.csv')):
+                name, row = page.split(',', 1)
+                pages.setdefault(name.rsplit('-', 1)[0], []).append(row.encode())
+        continue
+    if full:
+        continue
+    puzzle = line[:-len('-01.txt')]
+    if puzzle.split('-')[1] in known:
+        continue
+    if pages:
+        puzzle_rows = pages.get(puzzle, [])
+    else:
+        puzzle_rows = [open(f, 'rb').read() for f in sorted(glob.glob(os.path.join(directory, puzzle + '-[0-9][0-9].txt')))]
+    # rows end with the page number and the number of pages of the puzzle
+    if not puzzle_rows or len(puzzle_rows) != int(puzzle_rows[0].split(b',')[-1]):
+        sys.exit('%s: %d pages found in %s, not all of the puzzle' % (puzzle, len(puzzle_rows), directory))
+    rating = puzzle.split('-')[2]
+    rating = int(rating) if rating.isdigit() else 0
+    puzzle_rows = [encode_row(row, rating) for row in puzzle_rows]
+    if ${CONFIG_FLAGS} & ${FLAG_RLE}:
+        extra = (PUZZLE_ID_SIZE if ${CONFIG_FLAGS} & ${FLAG_PUZZLE_ID} else 0) + \
+            (ROW_META.size if ${CONFIG_FLAGS} & ${FLAG_ROW_META} else 0) + bool(${CONFIG_FLAGS} & ${FLAG_PAGE_CHECKSUM})
+        if any(len(rle_encode(row)) + extra > row_size for row in puzzle_rows):
+            oversized += 1
+            continue
+    padding = align_rows(size, row_size, len(puzzle_rows), ${SPLIT_SIZE}) if ${CONFIG_FLAGS} & ${FLAG_SECTOR_ALIGN} else 0
+    # stop when the whole puzzle does not fit anymore, with its index entry,
+    # or with --fill-tail when not even a puzzle of one page would
+    if index_offset(size + row_size * (padding + len(puzzle_rows))) + index_entry_size * (puzzles + 1) > ${DATA_SIZE}:
+        full = '${FILL_TAIL}' != 'true' or \
+            index_offset(size + row_size) + index_entry_size * (puzzles + 1) > ${DATA_SIZE}
+        continue
+    # padding rows are zeros, their checksum too
+    for _ in range(padding):
+        add_row(bytes(row_size))
+    if index_entry_size:
+        puzzle_id = puzzle.split('-')[1]
+        if len(puzzle_id) > INDEX_ENTRY.size - 8:
+            sys.exit('%s: id too long for the index' % puzzle)
+        index.append(INDEX_ENTRY.pack(puzzle_id.encode(), rows, len(puzzle_rows), rating))
+    meta = row_meta(rating, themes.get(puzzle, [])) if ${CONFIG_FLAGS} & ${FLAG_ROW_META} else b''
+    puzzle_id = b''
+    if ${CONFIG_FLAGS} & ${FLAG_PUZZLE_ID}:
+        if len(puzzle.split('-')[1]) > PUZZLE_ID_SIZE:
+            sys.exit('%s: id too long for the id field' % puzzle)
+        puzzle_id = puzzle.split('-')[1].encode().ljust(PUZZLE_ID_SIZE, b'\0')
+    for row in puzzle_rows:
+        write(puzzle, row, meta, puzzle_id)
+    puzzles += 1
+# the rows written are those of the types and of the sections
+if not rom.tell() == size == sum(t[1] * t[2] for t in types):
+    sys.exit('%d bytes of rows written, %d counted, the types have %d' % (
+        rom.tell(), size, sum(t[1] * t[2] for t in types)))
+if not rows == sum(t[2] for t in types) == sum(s[1] for s in sections):
+    sys.exit('%d rows written, the types have %d and the sections %d' % (
+        rows, sum(t[2] for t in types), sum(s[1] for s in sections)))
+end = size
+if index:
+    rom.write(b'\0' * (index_offset(size) - size) + b''.join(index))
+    end = index_offset(size) + len(index) * INDEX_ENTRY.size
+rom.write(bytes([${FILL_BYTE}]) * (${PROGRESS_OFFSET} - end))
+rom.write(b'\xff' * ${PROGRESS_SIZE})
+print(puzzles, rows, crc, size, index_offset(size) if index else 0, oversized, ','.join('%d:%d:%d' % tuple(t) for t in types) or '-',
+      ' '.join('%d:%d' % tuple(s) for s in sections))"
+}
+
+for dir in "${SECTION_DIRS[@]}"
 do
-    echo -n .
-    row_count=$((row_count+1))
-    # files ending in -01.txt are beginnings of puzzles
-    [ ${f: -6:2} = '01' ] && puzzle_count=$((puzzle_count+1))
-    cat ${f} >> ${ROMFILE}
-    padded_size=$((ROW_SIZE*row_count))
-    dd if=/dev/null of=${ROMFILE} obs=${padded_size} seek=1 status=none
-    FREE_SPACE=$((FLASH_SIZE-CONFIG_SECTOR_SIZE-padded_size))
-    [ ${FREE_SPACE} -lt ${MAX_PUZZLE_SIZE} ] && break
+    [[ ${ORDER} = "curriculum" && ! -f ${dir}/index.csv && ! -f ${dir}/text.txt ]] && \
+        { log error "${dir}/index.csv not found, run genfenpuzzles.sh again"; exit 1; }
 done
-echo
-echo "${puzzle_count} puzzles in ${padded_size} bytes..."
-
-echo "Padding with ${FREE_SPACE} bytes to fill up to config sector..."
-dd if=/dev/null of=${ROMFILE} obs=$((FLASH_SIZE-CONFIG_SECTOR_SIZE)) seek=1 status=none
-
-echo "Writing config sector..."
-python -c $"import sys
-from struct import pack
-
-# magic: u32 = 0x11131719
-sys.stdout.buffer.write(pack('<L', 0x11131719))
-# num_pages: u32 (a record is 1 page)
-sys.stdout.buffer.write(pack('<L', ${row_count}))
-# total_size: u32
-sys.stdout.buffer.write(pack('<L', $((row_count * ROW_SIZE))))
-# num_types: u8
-sys.stdout.buffer.write(pack('<B', 0x1))
-# font_size: u8
-sys.stdout.buffer.write(pack('<B', 0x1))
-# reserved0, reserved1
-sys.stdout.buffer.write(pack('<H', 0x0))
-# type0: u8
-sys.stdout.buffer.write(pack('<B', 0x4))
-# type1: u8
-sys.stdout.buffer.write(pack('<B', 0x0))
-# type2: u8
-sys.stdout.buffer.write(pack('<B', 0x0))
-# type3: u8
-sys.stdout.buffer.write(pack('<B', 0x0))
-# size0: u32
-sys.stdout.buffer.write(pack('<L', ${ROW_SIZE}))
-# size1: u32
-sys.stdout.buffer.write(pack('<L', 0)) 
-# size2: u32
-sys.stdout.buffer.write(pack('<L', 0))
-# size3: u32
-sys.stdout.buffer.write(pack('<L', 0))
-
-# fill the remaining config sector with zeros
-sys.stdout.buffer.write(b'\0' * (${CONFIG_SECTOR_SIZE} - ${CONFIG_STRUCT_SIZE}))" >> ${ROMFILE}
-
-echo Done
+RESULT=($(for dir in "${SECTION_DIRS[@]}"
+          do
+              echo "section ${dir}"
+              if [[ ${ORDER} = "curriculum" && ! -f ${dir}/text.txt ]]
+              then
+                  (cd ${dir} && curriculum_order)
+              else
+                  list_puzzles ${dir} | ${SORT}
+              fi
+          done | log_errors write_rows)) || { rm -f ${ROMFILE}; exit 1; }
+puzzle_count=${RESULT[0]}
+row_count=${RESULT[1]}
+data_crc32=${RESULT[2]}
+padded_size=${RESULT[3]}
+index_offset=${RESULT[4]}
+OVERSIZED=${RESULT[5]}
+# type:row_size:num_pages of every type slot in use
+TYPES=${RESULT[6]}
+# first_page:num_pages of every section
+SECTIONS=(${RESULT[@]:7})
+# after the progress dots
+[ ${LOG_FORMAT} = "json" ] || echo
+log info "${puzzle_count} puzzles in ${padded_size} bytes..."
+[ ${OVERSIZED} -gt 0 ] && \
+    log warn "${OVERSIZED} puzzles left out, a page of theirs does not fit a row of ${ROW_SIZE} bytes even run length encoded..."
+
+FREE_SPACE=$((PROGRESS_OFFSET-padded_size))
+[ ${index_offset} -gt 0 ] && \
+{
+    FREE_SPACE=$((PROGRESS_OFFSET-index_offset-puzzle_count*INDEX_ENTRY_SIZE))
+    log info "Index of ${puzzle_count} puzzles at ${index_offset}..."
+}
+log info "Padded with ${FREE_SPACE} bytes of ${FILL_BYTE} to fill up to config sector..."
+[ ${PROGRESS_SECTORS} -gt 0 ] && log info "Progress region of ${PROGRESS_SECTORS} sectors at ${PROGRESS_OFFSET}..."
+
+log info "Writing config sector..."
+log_errors python -c $"import sys
+from romtool import ConfigSector, filters_sha256
+
+types = [[int(n) for n in t.split(':')] for t in '${TYPES}'.split(',') if t != '-']
+# a rom without pages still says what it is for
+types = types or [[${CONTENT_TYPE}, ${ROW_SIZE}, 0]]
+sections = [[int(n) for n in s.split(':')] for s in '${SECTIONS[*]}'.split()]
+config = ConfigSector.build(types, sections, ${data_crc32}, font_size=${FONT_SIZE}, version=${CONFIG_VERSION}, flags=${CONFIG_FLAGS},
+                            index_offset=${index_offset}, index_count=${puzzle_count} if ${index_offset} else 0,
+                            progress_offset=${PROGRESS_OFFSET} if ${PROGRESS_SECTORS} else 0,
+                            progress_sectors=${PROGRESS_SECTORS},
+                            filters_sha256=filters_sha256(sys.argv[1:]), row_template='${ROW_TEMPLATE}',
+                            byte_order='${ENDIAN}')
+if (config.num_pages, config.total_size) != (${row_count}, ${padded_size}):
+    sys.exit('config of %d pages in %d bytes, %d rows of %d bytes were written' % (
+        config.num_pages, config.total_size, ${row_count}, ${padded_size}))
+sys.stdout.buffer.write(config.encode())" "${SECTION_DIRS[@]}" >> ${ROMFILE} || { rm -f ${ROMFILE}; exit 1; }
+
+if [ -n "${APPEND}" ]
+then
+    log info "Appending to ${APPEND}..."
+    TEMP_FILES+=(${OUTPUT_ROM}.tmp)
+    log_output info ./romtool.py merge --flash-size ${FLASH_SIZE} --fill-byte ${FILL_BYTE} \
+        --progress-sectors ${PROGRESS_SECTORS} --bank-size ${SPLIT_SIZE} -o ${OUTPUT_ROM}.tmp ${APPEND_ROM} ${ROMFILE} || exit 1
+    mv ${OUTPUT_ROM}.tmp ${OUTPUT_ROM}
+    ROMFILE=${OUTPUT_ROM}
+fi
+
+# checksum and manifest of the rom, to distribute it and know later what it
+# has.  The checksum file is the one of sha256sum, for sha256sum -c
+log info "Writing ${ROMFILE}.sha256 and $(dirname ${ROMFILE})/manifest.json..."
+log_errors python -c $"import hashlib, os
+digest = hashlib.sha256(open('${ROMFILE}', 'rb').read()).hexdigest()
+open('${ROMFILE}.sha256', 'w').write('%s  %s\n' % (digest, os.path.basename('${ROMFILE}')))" || exit 1
+log_errors ./romtool.py manifest -o $(dirname ${ROMFILE})/manifest.json ${ROMFILE} > /dev/null || exit 1
+
+# Themes and ratings of the puzzles from the index.csv of the sections,
+# unknown for those of an --append rom not in them, and skip reasons from
+# their skipped.csv
+REPORT_RATING_BAND=100
+function write_report() {
+    python -c $"import collections, json, os, sys
+from romtool import Rom
+
+rom = Rom('${ROMFILE}')
+puzzles = {}
+skipped = collections.Counter()
+for directory in sys.argv[1:]:
+    if os.path.exists(os.path.join(directory, 'index.csv')):
+        for line in open(os.path.join(directory, 'index.csv')):
+            name, rating, themes = line.rstrip('\n').split(',', 2)
+            puzzles[name.split('-')[1]] = (int(rating), themes.split())
+    if os.path.exists(os.path.join(directory, 'skipped.csv')):
+        for line in open(os.path.join(directory, 'skipped.csv')):
+            reason, count = line.rstrip('\n').split(',')
+            skipped[reason] += int(count)
+ids = list(rom.puzzles())
+themes = collections.Counter()
+bands = collections.Counter()
+for puzzle_id in ids:
+    rating, puzzle_themes = puzzles.get(puzzle_id, (-1, ['unknown']))
+    themes.update(puzzle_themes)
+    bands[rating // ${REPORT_RATING_BAND} * ${REPORT_RATING_BAND} if rating >= 0 else -1] += 1
+ratings = {'%d-%d' % (band, band + ${REPORT_RATING_BAND} - 1): count for band, count in sorted(bands.items()) if band >= 0}
+if bands[-1]:
+    ratings['unknown'] = bands[-1]
+report = {
+    'rom': '${ROMFILE}',
+    'flash_size': len(rom.data),
+    'puzzles': len(ids),
+    'pages': rom.num_pages,
+    'padding_pages': sum(1 for page in range(rom.num_pages) if rom.padding(page)),
+    'free_bytes': rom.free_end() - rom.end(),
+    'data_crc32': '0x%08x' % rom.config.data_crc32,
+    'themes': dict(sorted(themes.items())),
+    'ratings': ratings,
+    'skipped': dict(sorted(skipped.items())),
+    'puzzle_ids': ids,
+}
+json.dump(report, open('${REPORT}', 'w'), indent=2)" "${SECTION_DIRS[@]}"
+}
+
+if [ -n "${REPORT}" ]
+then
+    log info "Writing report to ${REPORT}..."
+    mkdir -p $(dirname ${REPORT})
+    log_errors write_report || exit 1
+fi
+
+if [ ${SPLIT_SIZE} -gt 0 ]
+then
+    log info "Splitting ${ROMFILE} in banks of ${SPLIT_SIZE} bytes..."
+    # the firmware reads the pages of a puzzle one after the other, they
+    # must all be in the same bank
+    log_output info ./romtool.py verify --bank-size ${SPLIT_SIZE} ${ROMFILE} || exit 1
+    python -c $"data = open('${ROMFILE}', 'rb').read()
+for bank, offset in enumerate(range(0, len(data), ${SPLIT_SIZE})):
+    open('${ROMFILE}.%d' % bank, 'wb').write(data[offset:offset + ${SPLIT_SIZE}])"
+fi
+
+if [ -n "${FORMAT}" ] && [ ${FORMAT} != "bin" ]
+then
+    log info "Writing ${ROMFILE%.*}.${FORMAT}..."
+    log_output info ./romtool.py convert --format ${FORMAT} ${FLASH_ADDRESS:+--address ${FLASH_ADDRESS}} \
+        ${ROMFILE} ${ROMFILE%.*}.${FORMAT} || exit 1
+fi
+
+log info Done
 
 # Unused = 0,
 # Text = 1,
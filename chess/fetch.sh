@@ -0,0 +1,64 @@
+#! /bin/bash
+
+# Download the lichess puzzle database to a cache directory and print its
+# path.  An interrupted download is resumed the next time, and a complete one
+# is reused until --refresh is given.
+#
+# NOTE: This script requires GNU getopt, see genfenpuzzles.sh
+GETOPT=/usr/bin/getopt
+[ -f /usr/local/opt/gnu-getopt/bin/getopt ] && GETOPT=/usr/local/opt/gnu-getopt/bin/getopt
+
+TEMP=$(${GETOPT} -o h --long help,cache-dir:,sha256:,refresh \
+              -n 'fetch.sh' -- "$@")
+
+if [ $? != 0 ] ; then echo "Terminating..." >&2 ; exit 1 ; fi
+
+# Note the quotes around '$TEMP': they are essential!
+eval set -- "$TEMP"
+
+function print_usage() {
+    echo "$0 <options>"
+    echo "--cache-dir: directory to download the puzzles to (default ~/.cache/lightnote)"
+    echo "--sha256: expected checksum of the download, by default it is looked up in ${SHA256_URL}"
+    echo "--refresh download the latest puzzles again even if there is a complete download"
+}
+
+URL=https://database.lichess.org/lichess_db_puzzle.csv.zst
+SHA256_URL=https://database.lichess.org/sha256sums.txt
+CACHE_DIR=~/.cache/lightnote
+SHA256=""
+REFRESH=false
+while true; do
+  case "$1" in
+    --cache-dir ) CACHE_DIR="$2"; shift 2 ;;
+    --sha256 ) SHA256="$2"; shift 2 ;;
+    --refresh ) REFRESH=true; shift ;;
+    -h | --help ) print_usage; exit 0; ;;
+    -- ) shift; break ;;
+    * ) break ;;
+  esac
+done
+
+PUZZLES_ZST=${CACHE_DIR}/${URL##*/}
+mkdir -p ${CACHE_DIR} || exit 1
+# a file is only complete once its checksum was verified
+[ ${REFRESH} = "true" ] && rm -f ${PUZZLES_ZST} ${PUZZLES_ZST}.ok
+if [ ! -f ${PUZZLES_ZST}.ok ]
+then
+    echo "Downloading ${URL}..." >&2
+    wget -q --show-progress -c -O ${PUZZLES_ZST} ${URL} >&2 || \
+        { echo "Download failed, run again to resume it" >&2; exit 1; }
+
+    [ -z "${SHA256}" ] && SHA256=$(wget -q -O - ${SHA256_URL} | grep " ${URL##*/}$" | cut -d' ' -f1)
+    if [ -z "${SHA256}" ]
+    then
+        echo "No checksum found for ${URL##*/}, not verified" >&2
+    elif [ "$(sha256sum < ${PUZZLES_ZST} | cut -d' ' -f1)" != "${SHA256}" ]
+    then
+        echo "Checksum mismatch, removing ${PUZZLES_ZST}" >&2
+        rm -f ${PUZZLES_ZST}
+        exit 1
+    fi
+    touch ${PUZZLES_ZST}.ok
+fi
+echo ${PUZZLES_ZST}
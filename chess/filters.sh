@@ -0,0 +1,464 @@
+# Puzzle filters used by genfenpuzzles.sh
+#
+# Every filter is a function called filter_<name> that looks at the puzzle
+# being processed (PUZZLE, FEN, FIRST_MOVE, RATING, MOVES, THEMES...) and
+# succeeds to keep it.  To skip it, a filter fails and sets SKIP_REASON.
+#
+# Filters are run in the order of the FILTERS pipeline, the first one failing
+# skips the puzzle.  A pipeline stage can also be "!name" to keep the puzzles
+# the filter would skip, or "name1|name2" to keep the puzzles any of the
+# filters keeps.  More filters can be defined in a file loaded with
+# --filters-file and added to the pipeline with --filter.
+
+FILTERS=(valid duplicate_id ids exclude_pieces require_pieces piece_count \
+    max_piece rating rating_deviation popularity plays moves promotions theme \
+    exclude_theme theme_quota max_per_theme opening unsupported_moves solutions only_mates \
+    last_moved_piece last_move_to_rank rating_buckets dedup_position \
+    similar_position max_per_game)
+
+# reason reported for the puzzles skipped by each filter, filters not listed
+# here are reported by name
+declare -A FILTER_REASON=( [valid]="parse error" [duplicate_id]="duplicate" \
+    [ids]="ids" [exclude_pieces]="pieces" [require_pieces]="pieces" [piece_count]="pieces" \
+    [max_piece]="pieces" [rating]="rating" [rating_deviation]="rating" \
+    [rating_buckets]="rating" [popularity]="popularity" [plays]="popularity" \
+    [moves]="moves" [promotions]="moves" [unsupported_moves]="moves" \
+    [solutions]="moves" [only_mates]="moves" [last_moved_piece]="moves" \
+    [last_move_to_rank]="moves" [theme]="theme" [exclude_theme]="theme" \
+    [theme_quota]="theme" [max_per_theme]="theme" [opening]="opening" \
+    [dedup_position]="duplicate" [similar_position]="duplicate" \
+    [max_per_game]="duplicate" )
+
+# run one stage of the pipeline, succeeds if the puzzle is kept
+function run_filter() {
+    local stage=$1
+    local alternative
+    case ${stage} in
+        *\|* )
+            for alternative in ${stage//|/ }
+            do
+                run_filter ${alternative} && return 0
+            done
+            return 1
+        ;;
+        !* )
+            run_filter ${stage#!} || return 0
+            SKIP_REASON="kept by ${stage#!}"
+            return 1
+        ;;
+        * )
+            filter_${stage}
+        ;;
+    esac
+}
+
+# apply every move of the solution, keeping the resulting positions and
+# moved pieces in FENS and MOVED_PIECES.  Stops at the first move move_fen
+# cannot apply, which is left in UNSUPPORTED_MOVE.  Only done once per puzzle
+function replay_solution() {
+    [ "${REPLAYED}" = "${PUZZLE[0]}" ] && return
+    REPLAYED=${PUZZLE[0]}
+    FENS=()
+    MOVED_PIECES=()
+    UNSUPPORTED_MOVE=""
+    local fen=${FEN}
+    local move
+    local IFS=$'\n'
+    for move in ${MOVES[@]}
+    do
+        # null move, see filter_valid
+        if [ ${move} = "0000" ]
+        then
+            FENS+=(${fen})
+            MOVED_PIECES+=(-)
+            continue
+        fi
+        is_unsupported_move $move $(expand_fen $fen) && { UNSUPPORTED_MOVE=${move}; break; }
+        # output contains two return values, this is why OUT is put into array
+        OUT=($(move_fen $move $fen))
+        fen=${OUT[0]}
+        FENS+=(${OUT[0]})
+        MOVED_PIECES+=(${OUT[1]})
+    done
+}
+
+# filter out rows that are not a well formed puzzle
+function filter_valid() {
+    local move
+    [ ${#PUZZLE[@]} -ge 8 ] || { SKIP_REASON="missing columns"; return 1; }
+    [[ ${FEN} =~ ^([pnbrqkPNBRQK1-8]+/){7}[pnbrqkPNBRQK1-8]+$ && ${FIRST_MOVE} =~ ^[wb]$ ]] || \
+        { SKIP_REASON="invalid fen ${PUZZLE[1]}"; return 1; }
+    [[ ${RATING} =~ ^[0-9]+$ && ${RATING_DEVIATION} =~ ^[0-9]+$ && \
+        ${POPULARITY} =~ ^-?[0-9]+$ && ${NB_PLAYS} =~ ^[0-9]+$ ]] || \
+        { SKIP_REASON="invalid numeric column"; return 1; }
+    [ ${#MOVES[@]} -gt 0 ] || { SKIP_REASON="no moves"; return 1; }
+    for move in ${MOVES[@]}
+    do
+        [[ ${move} =~ ^([a-h][1-8][a-h][1-8][qrbn]?|0000)$ ]] || { SKIP_REASON="invalid move ${move}"; return 1; }
+    done
+    # the opponent can pass (null move 0000) when the solver moves first
+    [[ " ${MOVES[*]:1} " =~ " 0000 " ]] && { SKIP_REASON="null move after the first move"; return 1; }
+    return 0
+}
+
+# filter out puzzles already included, when reading several files with the
+# same puzzle
+function filter_duplicate_id() {
+    [ -n "${ACCEPTED_IDS[${PUZZLE[0]}]}" ] && { SKIP_REASON="already included"; return 1; }
+    return 0
+}
+
+# filter out by id lists
+function filter_ids() {
+    [[ -n "${INCLUDE_IDS_FILE}" && -z "${INCLUDE_IDS[${PUZZLE[0]}]}" ]] && \
+        { SKIP_REASON="not in include-ids"; return 1; }
+    [ -n "${EXCLUDE_IDS[${PUZZLE[0]}]}" ] && \
+        { SKIP_REASON="in exclude-ids"; return 1; }
+    [ -n "${KNOWN_IDS[${PUZZLE[0]}]}" ] && \
+        { SKIP_REASON="not newer than ${NEWER_THAN}"; return 1; }
+    return 0
+}
+
+# filter out specific pieces, ignore case (color)
+function filter_exclude_pieces() {
+    [[ ${FEN,,} == *["${EXCLUDE_PIECES,,}"]* ]] && \
+        { SKIP_REASON="contains pieces from blacklist"; return 1; }
+    return 0
+}
+
+# filter out puzzles missing required pieces. The solver plays the side
+# that is not to move in the fen, so swap cases when the solver is black
+function filter_require_pieces() {
+    local required="${REQUIRE_PIECES}"
+    local i
+    [ ${FIRST_MOVE} = 'w' ] && required="${required~~}"
+    for (( i=0; i<${#required}; i++ ))
+    do
+        [[ ${FEN} == *"${required:$i:1}"* ]] || \
+            { SKIP_REASON="missing required piece ${required:$i:1}"; return 1; }
+    done
+    return 0
+}
+
+# filter out by number of pieces on the board
+function filter_piece_count() {
+    local piece_count=$(count_pieces ${FEN})
+    [ ${piece_count} -lt ${MIN_PIECES} -o ${piece_count} -gt ${MAX_PIECES} ] && \
+        { SKIP_REASON="${piece_count} pieces out of range"; return 1; }
+    return 0
+}
+
+# filter out by maximum count of a piece type
+function filter_max_piece() {
+    local limit piece too_many=""
+    local IFS=","
+    for limit in ${MAX_PIECE}
+    do
+        piece=${limit%=*}
+        if [[ ${piece} == [a-z] ]]
+        then
+            [ $(count_pieces ${FEN} "${piece}${piece^}") -gt ${limit#*=} ] && too_many=${piece}
+        else
+            [ $(count_pieces ${FEN} "${piece}") -gt ${limit#*=} ] && too_many=${piece}
+            [ $(count_pieces ${FEN} "${piece,}") -gt ${limit#*=} ] && too_many=${piece}
+        fi
+        [ -n "${too_many}" ] && { SKIP_REASON="too many ${too_many}"; return 1; }
+    done
+    return 0
+}
+
+# filter out by rating, the first --theme-rating matching the puzzle themes
+# wins over the min/max ratings
+function filter_rating() {
+    local max_rating=${MAX_RATING}
+    local min_rating=${MIN_RATING}
+    local theme_rating range
+    for theme_rating in "${THEME_RATINGS[@]}"
+    do
+        [[ " ${THEMES[*]} " == *" ${theme_rating%=*} "* ]] || continue
+        range=${theme_rating#*=}
+        min_rating=${range%-*}
+        max_rating=${range#*-}
+        break
+    done
+
+    # filter out easy puzzles
+    [ ${RATING} -gt ${max_rating} ] && { SKIP_REASON="too easy"; return 1; }
+    # filter out hard puzzles
+    [ ${RATING} -lt ${min_rating} ] && { SKIP_REASON="too hard"; return 1; }
+    return 0
+}
+
+# filter out puzzles with a provisional rating
+function filter_rating_deviation() {
+    [ ${RATING_DEVIATION} -gt ${MAX_RATING_DEVIATION} ] && \
+        { SKIP_REASON="rating deviation too high"; return 1; }
+    return 0
+}
+
+# filter out unpopular puzzles
+function filter_popularity() {
+    [ ${POPULARITY} -lt ${MIN_POPULARITY} ] && \
+        { SKIP_REASON="not popular enough"; return 1; }
+    return 0
+}
+
+# filter out rarely played puzzles
+function filter_plays() {
+    [ ${NB_PLAYS} -lt ${MIN_PLAYS} ] && \
+        { SKIP_REASON="not played enough"; return 1; }
+    return 0
+}
+
+# filter out long and short puzzles
+function filter_moves() {
+    [ ${#MOVES[@]} -gt ${MAX_MOVES} ] && { SKIP_REASON="too long"; return 1; }
+    [ ${#MOVES[@]} -lt ${MIN_MOVES} ] && { SKIP_REASON="too short"; return 1; }
+    return 0
+}
+
+# filter out by promotions, promoting moves have the new piece appended
+function filter_promotions() {
+    local has_promotion=false
+    [[ " ${MOVES[*]}" =~ \ [a-h][1-8][a-h][1-8][qrbn] ]] && has_promotion=true
+    [[ ${PROMOTIONS} = "exclude" && ${has_promotion} = "true" ]] || \
+        [[ ${PROMOTIONS} = "only" && ${has_promotion} = "false" ]] && \
+        { SKIP_REASON="promotions ${PROMOTIONS}"; return 1; }
+    return 0
+}
+
+# succeed if any theme of the puzzle matches the glob pattern $1, the whole
+# theme must match so "mate" does not match "mateIn2", or is exactly $1 when
+# $2 is "exact"
+function has_theme() {
+    local pattern=$1
+    local theme
+    for theme in ${THEMES[@]}
+    do
+        if [ "$2" = "exact" ]
+        then
+            [[ ${theme} == "${pattern}" ]] && return 0
+        else
+            [[ ${theme} == ${pattern} ]] && return 0
+        fi
+    done
+    return 1
+}
+
+# filter out by theme, every pattern has to match one of the themes
+function filter_theme() {
+    local pattern
+    [[ ${THEME_TAG} != "none" && ! " ${THEMES[*]} " =~ " ${THEME_TAG} " ]] && \
+        { SKIP_REASON="wrong theme"; return 1; }
+    for pattern in "${THEME_PATTERNS[@]}"
+    do
+        has_theme "${pattern}" || { SKIP_REASON="no theme matching ${pattern}"; return 1; }
+    done
+    for pattern in "${THEME_EXACTS[@]}"
+    do
+        has_theme "${pattern}" exact || { SKIP_REASON="no theme ${pattern}"; return 1; }
+    done
+    return 0
+}
+
+# filter out excluded themes
+function filter_exclude_theme() {
+    local pattern
+    for pattern in "${EXCLUDE_THEMES[@]}"
+    do
+        has_theme "${pattern}" && \
+            { SKIP_REASON="excluded theme ${pattern}"; return 1; }
+    done
+    return 0
+}
+
+# filter out puzzles with a theme that already reached its quota
+function filter_theme_quota() {
+    local theme
+    for theme in ${THEMES[@]}
+    do
+        [ -n "${THEME_QUOTA[$theme]}" ] || continue
+        [ ${THEME_COUNT[$theme]} -ge ${THEME_QUOTA[$theme]} ] && \
+        {
+            THEME_OVERFLOW[${theme}]=$(( ${THEME_OVERFLOW[${theme}]} + 1 ))
+            SKIP_REASON="quota of theme ${theme} reached"
+            return 1
+        }
+    done
+    return 0
+}
+
+# filter out puzzles with a theme that already has MAX_PER_THEME puzzles
+function filter_max_per_theme() {
+    [ ${MAX_PER_THEME} -eq 0 ] && return 0
+    local theme
+    for theme in ${THEMES[@]}
+    do
+        [ ${PER_THEME_COUNT[$theme]:-0} -ge ${MAX_PER_THEME} ] && \
+            { SKIP_REASON="already ${MAX_PER_THEME} puzzles with theme ${theme}"; return 1; }
+    done
+    return 0
+}
+
+# filter out by opening
+function filter_opening() {
+    [[ ${OPENING_TAG} != "none" && ! " ${OPENINGS[*]} " =~ " ${OPENING_TAG} " ]] && \
+        { SKIP_REASON="wrong opening"; return 1; }
+    return 0
+}
+
+# filter out puzzles with castling or en passant in the solution
+function filter_unsupported_moves() {
+    replay_solution
+    [ -n "${UNSUPPORTED_MOVE}" ] && \
+        { SKIP_REASON="unsupported move ${UNSUPPORTED_MOVE}"; return 1; }
+    return 0
+}
+
+# filter out by kind of solver moves, the solver plays the odd moves
+function filter_solutions() {
+    [ ${SOLUTIONS} = "any" ] && return 0
+    replay_solution
+    local i capture
+    for (( i=1; i<${#MOVES[@]}; i+=2 ))
+    do
+        is_capture ${MOVES[$i]} $(expand_fen ${FENS[$((i - 1))]}) && capture="a capture" || capture="quiet"
+        [[ ${SOLUTIONS} = "captures-only" && ${capture} = "quiet" ]] || \
+            [[ ${SOLUTIONS} = "quiet-only" && ${capture} != "quiet" ]] && \
+            { SKIP_REASON="solver move ${MOVES[$i]} is ${capture}"; return 1; }
+    done
+    return 0
+}
+
+# filter out puzzles that do not end in checkmate, regardless of theme tags
+function filter_only_mates() {
+    [ "${ONLY_MATES}" = "true" ] || return 0
+    replay_solution
+    # the side to move after the last move is the one that has to be mated
+    local mated_side=${FIRST_MOVE}
+    if [ $(( ${#MOVES[@]} % 2 )) -eq 1 ]
+    then
+        [ ${FIRST_MOVE} = 'w' ] && mated_side=b || mated_side=w
+    fi
+    is_checkmate $(expand_fen ${FENS[-1]}) ${mated_side} || \
+        { SKIP_REASON="does not end in checkmate"; return 1; }
+    return 0
+}
+
+# filter out by last moved piece
+function filter_last_moved_piece() {
+    replay_solution
+    local moved_piece=${MOVED_PIECES[-1]}
+    [[ ${moved_piece,,} == *["${LAST_MOVED_PIECES,,}"]* ]] || \
+        { SKIP_REASON="last move piece ${moved_piece} not in last-move-pieces set"; return 1; }
+    return 0
+}
+
+# filter out by the rank the last solver move ends on, ranks are counted
+# from the solver side so 8 is always the opponent back rank
+function filter_last_move_to_rank() {
+    [ ${LAST_MOVE_TO_RANK} = "any" ] && return 0
+    local rank=${LAST_MOVE_TO_RANK}
+    [ ${rank} = "back-rank" ] && rank=8
+    local last_solver_move=${MOVES[-1]}
+    [ $(( ${#MOVES[@]} % 2 )) -eq 1 ] && last_solver_move=${MOVES[-2]}
+    local to_rank=${last_solver_move:3:1}
+    [ ${FIRST_MOVE} = 'w' ] && to_rank=$(( 9 - to_rank ))
+    [ ${to_rank} -ne ${rank} ] && \
+        { SKIP_REASON="last solver move ${last_solver_move} not to rank ${rank}"; return 1; }
+    return 0
+}
+
+# filter out puzzles outside of the rating buckets or whose bucket is full.
+# The bucket is left in BUCKET for accept_puzzle
+function filter_rating_buckets() {
+    [ ${#BUCKETS[@]} -gt 0 ] || return 0
+    local i
+    BUCKET=-1
+    for (( i=0; i<${#BUCKETS[@]}; i++ ))
+    do
+        [[ ${RATING} -ge ${BUCKET_LOW[$i]} && ${RATING} -lt ${BUCKET_HIGH[$i]} ]] && { BUCKET=$i; break; }
+    done
+    [ ${BUCKET} -lt 0 ] && { SKIP_REASON="rating not in any bucket"; return 1; }
+    [ ${BUCKET_COUNT[$BUCKET]} -ge ${BUCKET_QUOTA[$BUCKET]} ] && \
+        { SKIP_REASON="rating bucket ${BUCKETS[$BUCKET]} is full"; return 1; }
+    return 0
+}
+
+# filter out duplicated start positions. The fen itself is the key, so
+# unlike a hash there cannot be collisions
+function filter_dedup_position() {
+    [ ${DEDUP_POSITION} = "none" ] && return 0
+    local key
+    key=$(position_key)
+    [ -n "${SEEN_POSITIONS[${key}]}" ] && \
+        { SKIP_REASON="same position as ${SEEN_POSITIONS[${key}]}"; return 1; }
+    return 0
+}
+
+function position_key() {
+    local IFS=" "
+    local key="${FULL_FEN[0]} ${FULL_FEN[1]}"
+    [ ${DEDUP_POSITION} = "move" ] && key+=" ${MOVES[1]}"
+    [ ${DEDUP_POSITION} = "solution" ] && key+=" ${MOVES[*]}"
+    echo "${key}"
+}
+
+# filter out puzzles too similar to an already included one: same first
+# solver move and at least SIMILAR_SQUARES identical squares in the position
+# the solver is shown.  A puzzle of a single move has no solver move to compare
+function filter_similar_position() {
+    [ ${SIMILAR_SQUARES} -eq 0 -o ${#MOVES[@]} -lt 2 ] && return 0
+    replay_solution
+    local IFS=" "
+    local efen=$(expand_fen ${FENS[0]})
+    local seen id same i
+    for seen in ${SIMILAR_POSITIONS[${MOVES[1]}]}
+    do
+        id=${seen%:*}
+        seen=${seen#*:}
+        same=0
+        for (( i=0; i<64; i++ ))
+        do
+            [ "${efen:$i:1}" = "${seen:$i:1}" ] && same=$((same + 1))
+        done
+        [ ${same} -ge ${SIMILAR_SQUARES} ] && \
+            { SKIP_REASON="similar to ${id}, ${same} identical squares"; return 1; }
+    done
+    return 0
+}
+
+# game a puzzle comes from, the id in the GameUrl
+# (e.g. https://lichess.org/787zsVup/black#48)
+function game_id() {
+    local game=${PUZZLE[8]#*://*/}
+    echo ${game%%[/#]*}
+}
+
+# filter out puzzles from a game that already has MAX_PER_GAME puzzles
+function filter_max_per_game() {
+    [ ${MAX_PER_GAME} -eq 0 ] && return 0
+    local game=$(game_id)
+    [ ${GAME_COUNT[${game}]:-0} -ge ${MAX_PER_GAME} ] && \
+        { SKIP_REASON="already ${MAX_PER_GAME} puzzles from game ${game}"; return 1; }
+    return 0
+}
+
+# update the state of the filters that depend on the puzzles taken so far
+function accept_puzzle() {
+    local theme
+    [ ${DEDUP_POSITION} != "none" ] && SEEN_POSITIONS[$(position_key)]=${PUZZLE[0]}
+    [ ${SIMILAR_SQUARES} -gt 0 -a ${#MOVES[@]} -ge 2 ] && \
+        SIMILAR_POSITIONS[${MOVES[1]}]+=" ${PUZZLE[0]}:$(expand_fen ${FENS[0]})"
+    [ ${MAX_PER_GAME} -gt 0 ] && GAME_COUNT[$(game_id)]=$(( ${GAME_COUNT[$(game_id)]:-0} + 1 ))
+    if [ ${#BUCKETS[@]} -gt 0 ]
+    then
+        BUCKET_COUNT[$BUCKET]=$(( ${BUCKET_COUNT[$BUCKET]} + 1 ))
+        [ ${BUCKET_COUNT[$BUCKET]} -eq ${BUCKET_QUOTA[$BUCKET]} ] && full_buckets=$((full_buckets + 1))
+    fi
+    for theme in ${THEMES[@]}
+    do
+        [ -n "${THEME_QUOTA[$theme]}" ] && THEME_COUNT[$theme]=$(( ${THEME_COUNT[$theme]} + 1 ))
+        PER_THEME_COUNT[$theme]=$(( ${PER_THEME_COUNT[$theme]:-0} + 1 ))
+    done
+}
@@ -0,0 +1,93 @@
+#! /bin/bash
+
+# Write a rom to the board, after checking it with romtool.py verify:
+# copied as uf2 to the drive the board shows when started in its bootloader
+# (RPI-RP2), or loaded with picotool, or written to the flash chip with
+# flashrom and an SPI programmer.  By default the first of them found is used.
+#
+# NOTE: This script requires GNU getopt, see genfenpuzzles.sh
+GETOPT=/usr/bin/getopt
+[ -f /usr/local/opt/gnu-getopt/bin/getopt ] && GETOPT=/usr/local/opt/gnu-getopt/bin/getopt
+
+TEMP=$(${GETOPT} -o h --long help,tool:,mount:,programmer:,flash-address: \
+              -n 'flash.sh' -- "$@")
+
+if [ $? != 0 ] ; then echo "Terminating..." >&2 ; exit 1 ; fi
+
+# Note the quotes around '$TEMP': they are essential!
+eval set -- "$TEMP"
+
+function print_usage() {
+    echo "$0 <options> [ROM]"
+    echo "ROM: the rom to flash (default lightnote.rom)"
+    echo "--tool: uf2, picotool or flashrom (default the first available: uf2 when the RPI-RP2 drive is mounted, then picotool, then flashrom)"
+    echo "--mount: where the RPI-RP2 drive is mounted, for uf2 (default looked up in /media, /run/media and /Volumes)"
+    echo "--programmer: flashrom programmer the flash chip is connected with (default ch341a_spi)"
+    echo "--flash-address: address the rom is flashed at, for uf2 and picotool (default 0x10000000, the start of the RP2040 flash)"
+}
+
+TOOL=""
+MOUNT=""
+PROGRAMMER=ch341a_spi
+FLASH_ADDRESS=""
+while true; do
+  case "$1" in
+    --tool ) TOOL="$2"; shift 2 ;;
+    --mount ) MOUNT="$2"; shift 2 ;;
+    --programmer ) PROGRAMMER="$2"; shift 2 ;;
+    --flash-address ) FLASH_ADDRESS="$2"; shift 2 ;;
+    -h | --help ) print_usage; exit 0; ;;
+    -- ) shift; break ;;
+    * ) break ;;
+  esac
+done
+ROMFILE=${1:-lightnote.rom}
+
+[ -f ${ROMFILE} ] || { echo "No rom ${ROMFILE}, make it with fenpuzzles_toflash.sh" >&2; exit 1; }
+./romtool.py verify ${ROMFILE} || { echo "Not flashing ${ROMFILE}" >&2; exit 1; }
+
+[ -z "${MOUNT}" ] && MOUNT=$(ls -d /media/*/RPI-RP2 /run/media/*/RPI-RP2 /Volumes/RPI-RP2 2>/dev/null | head -1)
+if [ -z "${TOOL}" ]
+then
+    if [ -n "${MOUNT}" ] && [ -d ${MOUNT} ]
+    then
+        TOOL=uf2
+    elif which picotool > /dev/null 2>&1
+    then
+        TOOL=picotool
+    elif which flashrom > /dev/null 2>&1
+    then
+        TOOL=flashrom
+    else
+        echo "No RPI-RP2 drive mounted, nor picotool or flashrom found" >&2
+        echo "Start the board in its bootloader, holding BOOTSEL, or give --mount" >&2
+        exit 1
+    fi
+fi
+
+# picotool tells the files by their extension
+UF2_DIR=$(mktemp -d)
+trap 'rm -fr ${UF2_DIR}' EXIT
+UF2=${UF2_DIR}/lightnote.uf2
+case ${TOOL} in
+    uf2 )
+        [ -n "${MOUNT}" ] && [ -d ${MOUNT} ] || { echo "No RPI-RP2 drive mounted, give --mount" >&2; exit 1; }
+        ./romtool.py convert --format uf2 ${FLASH_ADDRESS:+--address ${FLASH_ADDRESS}} ${ROMFILE} ${UF2} || exit 1
+        echo "Copying ${ROMFILE} to ${MOUNT}..."
+        # the board restarts once the whole file is written
+        cp ${UF2} ${MOUNT}/lightnote.uf2 && sync || exit 1
+        ;;
+    picotool )
+        ./romtool.py convert --format uf2 ${FLASH_ADDRESS:+--address ${FLASH_ADDRESS}} ${ROMFILE} ${UF2} || exit 1
+        echo "Loading ${ROMFILE} with picotool..."
+        picotool load -v ${UF2} && picotool reboot || exit 1
+        ;;
+    flashrom )
+        # the rom is the size of the flash chip, it is written whole
+        echo "Writing ${ROMFILE} with flashrom..."
+        flashrom -p ${PROGRAMMER} -w ${ROMFILE} || exit 1
+        ;;
+    * ) echo "Unknown tool ${TOOL}, uf2, picotool or flashrom" >&2; exit 1 ;;
+esac
+
+echo Done
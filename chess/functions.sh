@@ -81,6 +81,148 @@ function ord() {
   LC_CTYPE=C printf '%d' "'$1"
 }
 
+# convert a size with an optional K or M suffix (e.g. 512K, 4M) to bytes
+function to_bytes() {
+    local size=$1
+    case ${size} in
+        *[kK] ) echo $(( ${size%?} * 1024 )) ;;
+        *[mM] ) echo $(( ${size%?} * 1024 * 1024 )) ;;
+        * ) echo $(( size )) ;;
+    esac
+}
+
+# print the message $2... at level $1, one of error, warn, info, debug or
+# trace.  Errors and warnings go to stderr, the rest to stdout, debug only
+# from LOG_LEVEL 1 (-v) and trace from LOG_LEVEL 2 (-vv).  With LOG_FORMAT json
+# every message is a JSON object on its own line, for CI logs
+function log() {
+    local level=$1
+    shift
+    case ${level} in
+        debug ) [ ${LOG_LEVEL:-0} -ge 1 ] || return 0 ;;
+        trace ) [ ${LOG_LEVEL:-0} -ge 2 ] || return 0 ;;
+    esac
+    local message="$*"
+    if [ "${LOG_FORMAT:-text}" = "json" ]
+    then
+        local time
+        TZ=UTC printf -v time '%(%Y-%m-%dT%H:%M:%SZ)T' -1
+        message=${message//\\/\\\\}
+        message=${message//\"/\\\"}
+        message=${message//$'\n'/\\n}
+        message=${message//$'\t'/\\t}
+        message=${message//$'\r'/\\r}
+        # the other control characters, e.g. from a field of the input
+        local char code
+        while [[ ${message} =~ [[:cntrl:]] ]]
+        do
+            char=${BASH_REMATCH[0]}
+            printf -v code '\\u%04x' "'${char}"
+            message=${message//"${char}"/${code}}
+        done
+        printf -v message '{"time":"%s","level":"%s","script":"%s","message":"%s"}' \
+            ${time} ${level} ${0##*/} "${message}"
+    fi
+    case ${level} in
+        error | warn ) echo "${message}" >&2 ;;
+        * ) echo "${message}" ;;
+    esac
+}
+
+# log every line read at level $1
+function log_lines() {
+    local line
+    while IFS= read -r line || [ -n "${line}" ]
+    do
+        log $1 "${line}"
+    done
+}
+
+# run a command, with LOG_FORMAT json its stderr logged as errors, keeping
+# its exit status
+function log_errors() {
+    [ "${LOG_FORMAT:-text}" = "json" ] || { "$@"; return; }
+    local pipefail status
+    pipefail=$(shopt -po pipefail)
+    set -o pipefail
+    { "$@" 2>&1 1>&4 4>&- | log_lines error; } 4>&1
+    status=$?
+    eval "${pipefail}"
+    return ${status}
+}
+
+# run a command, with LOG_FORMAT json its output logged at level $1 and its
+# stderr as errors, keeping its exit status
+function log_output() {
+    local level=$1
+    shift
+    [ "${LOG_FORMAT:-text}" = "json" ] || { "$@"; return; }
+    log_errors "$@" | log_lines ${level}
+    return ${PIPESTATUS[0]}
+}
+
+# write the zstd compressed file $1 decompressed to stdout, as it is being
+# decompressed so the whole lichess dump is never stored uncompressed.  Uses
+# the zstd tool or, when not installed, the python zstandard module
+function zstd_cat() {
+    if command -v zstd > /dev/null
+    then
+        zstd -dc "$1"
+    else
+        python -c $"import shutil, sys, zstandard
+
+with open(sys.argv[1], 'rb') as f:
+    shutil.copyfileobj(zstandard.ZstdDecompressor().stream_reader(f), sys.stdout.buffer)" "$1" || \
+            { echo "Install zstd or the python zstandard module to read $1" >&2; return 1; }
+    fi
+}
+
+# write the rows returned by the query $2 on the sqlite database $1 to stdout
+# as a puzzles csv, with the column names as header
+function sqlite_cat() {
+    python -c $"import sqlite3, sys
+
+rows = sqlite3.connect(sys.argv[1]).execute(sys.argv[2])
+print(','.join(column[0] for column in rows.description))
+for row in rows:
+    print(','.join('' if value is None else str(value) for value in row))" "$1" "$2"
+}
+
+# write the csv read from stdin with delimiter $1 and quote character $2 (none
+# when empty) to stdout as plain comma separated columns.  Commas within a
+# column are replaced by spaces
+function csv_cat() {
+    python -c $"import csv, sys
+
+quoting = {'quotechar': sys.argv[2]} if sys.argv[2] else {'quoting': csv.QUOTE_NONE}
+for row in csv.reader(sys.stdin, delimiter=sys.argv[1], **quoting):
+    print(','.join(column.replace(',', ' ') for column in row))" "$1" "$2"
+}
+
+# fill the associative array named by $2 with the puzzle ids listed in file
+# $1, one per line.  Anything after a comma is ignored so a puzzle csv works
+# too, as do empty lines and lines starting with #
+function load_ids() {
+    local file=$1
+    local -n ids=$2
+    local id rest
+    while IFS=',' read -r id rest
+    do
+        id=${id%$'\r'}
+        [[ -z ${id} || ${id} == \#* ]] && continue
+        ids[$id]=1
+    done < ${file}
+}
+
+# count the pieces on the board part of a fen, optionally only those in the
+# given set of piece letters (case sensitive, e.g. "Qq" for both queens)
+function count_pieces() {
+    local fen=$1
+    local set=${2:-prnbkqPRNBKQ}
+    local pieces=${fen//[^${set}]/}
+    echo ${#pieces}
+}
+
 # flatten and convert all numbers to 1's so that fen is represented in exactly
 # in a 64 chars string
 function expand_fen() {
@@ -166,6 +308,32 @@ function move_to_i() {
     printf %02d,%02d $from $to
 }
 
+# succeeds if a move in the form "d2d4" captures a piece on the given
+# expanded fen
+function is_capture() {
+    local move=$1
+    local efen=$2
+    local to=$(( $(ord ${move:2:1}) - 97 + (8 - ${move:3:1}) * 8 ))
+    [ "${efen:$to:1}" != "1" ]
+}
+
+# succeeds if a move in the form "e1g1", played on the given expanded fen, is a
+# castling or an en passant capture.  move_fen does not know how to apply
+# those: it would leave the rook behind or the captured pawn on the board.
+function is_unsupported_move() {
+    local move=$1
+    local efen=$2
+    local from=$(( $(ord ${move:0:1}) - 97 + (8 - ${move:1:1}) * 8 ))
+    local to=$(( $(ord ${move:2:1}) - 97 + (8 - ${move:3:1}) * 8 ))
+    local piece=${efen:$from:1}
+
+    # castling: the king moves two files
+    [[ ${piece,,} = "k" && $(( to - from )) =~ ^-?2$ ]] && return 0
+    # en passant: a pawn captures diagonally onto an empty square
+    [[ ${piece,,} = "p" && $(( (to - from) % 8 )) -ne 0 && "${efen:$to:1}" = "1" ]] && return 0
+    return 1
+}
+
 # input is a move in the form "d2d4" followed by the fen array
 # new fen and moved_piece are returned on stdout
 function move_fen() {
@@ -224,6 +392,152 @@ function reverse_fen() {
     echo ${REVERSED_ARRAY[@]}
 }
 
+# succeeds if square i (0 is a8, 63 is h1) of an expanded fen is attacked by a
+# piece of the given color (w or b)
+function square_attacked() {
+    local efen=$1
+    local i=$2
+    local color=$3
+    local row=$((i / 8))
+    local col=$((i % 8))
+    local pawn_dir knight king bishop rook queen
+    local r c d dr dc p
+    # callers change IFS freely, the lists below are space separated
+    local IFS=$' \t\n'
+
+    if [ ${color} = "w" ]
+    then
+        pawn_dir=1; knight=N; king=K; bishop=B; rook=R; queen=Q; p=P
+    else
+        pawn_dir=-1; knight=n; king=k; bishop=b; rook=r; queen=q; p=p
+    fi
+
+    # pawns attack diagonally towards the opposite side
+    r=$((row + pawn_dir))
+    for c in $((col - 1)) $((col + 1))
+    do
+        [[ $r -ge 0 && $r -le 7 && $c -ge 0 && $c -le 7 ]] || continue
+        [ "${efen:$((r * 8 + c)):1}" = "$p" ] && return 0
+    done
+
+    for d in -2,-1 -2,1 -1,-2 -1,2 1,-2 1,2 2,-1 2,1
+    do
+        r=$((row + ${d%,*})); c=$((col + ${d#*,}))
+        [[ $r -ge 0 && $r -le 7 && $c -ge 0 && $c -le 7 ]] || continue
+        [ "${efen:$((r * 8 + c)):1}" = "$knight" ] && return 0
+    done
+
+    for d in -1,-1 -1,0 -1,1 0,-1 0,1 1,-1 1,0 1,1
+    do
+        dr=${d%,*}; dc=${d#*,}
+        r=$((row + dr)); c=$((col + dc))
+        [[ $r -ge 0 && $r -le 7 && $c -ge 0 && $c -le 7 ]] || continue
+        [ "${efen:$((r * 8 + c)):1}" = "$king" ] && return 0
+        # slide until the first piece in this direction
+        while [[ $r -ge 0 && $r -le 7 && $c -ge 0 && $c -le 7 ]]
+        do
+            p=${efen:$((r * 8 + c)):1}
+            if [ "$p" != "1" ]
+            then
+                [ "$p" = "$queen" ] && return 0
+                [[ $dr -eq 0 || $dc -eq 0 ]] && [ "$p" = "$rook" ] && return 0
+                [[ $dr -ne 0 && $dc -ne 0 ]] && [ "$p" = "$bishop" ] && return 0
+                break
+            fi
+            r=$((r + dr)); c=$((c + dc))
+        done
+    done
+    return 1
+}
+
+# succeeds if the king of the given color (w or b) is in check
+function is_in_check() {
+    local efen=$1
+    local color=$2
+    local king=K enemy=b
+    [ ${color} = "b" ] && { king=k; enemy=w; }
+    local before=${efen%%${king}*}
+    square_attacked ${efen} ${#before} ${enemy}
+}
+
+# succeeds if the side of the given color (w or b) is checkmated.  Castling is
+# never a way out of check; en passant captures are not considered.
+function is_checkmate() {
+    local efen=$1
+    local color=$2
+    local own="[A-Z]" enemy=b king=K pawn_dir=-1 pawn_row=6
+    [ ${color} = "b" ] && { own="[a-z]"; enemy=w; king=k; pawn_dir=1; pawn_row=1; }
+    local before=${efen%%${king}*}
+    local king_i=${#before}
+    local from to row col r c d dr dc p piece targets moved king_to
+    local IFS=$' \t\n'
+
+    square_attacked ${efen} ${king_i} ${enemy} || return 1
+
+    for (( from=0; from<64; from++ ))
+    do
+        piece=${efen:$from:1}
+        [[ ${piece} == ${own} ]] || continue
+        row=$((from / 8)); col=$((from % 8))
+        targets=()
+        case ${piece,,} in
+            "p")
+                r=$((row + pawn_dir))
+                if [ "${efen:$((r * 8 + col)):1}" = "1" ]
+                then
+                    targets+=($((r * 8 + col)))
+                    [[ $row -eq $pawn_row && "${efen:$(((r + pawn_dir) * 8 + col)):1}" = "1" ]] && \
+                        targets+=($(((r + pawn_dir) * 8 + col)))
+                fi
+                for c in $((col - 1)) $((col + 1))
+                do
+                    [[ $c -ge 0 && $c -le 7 ]] || continue
+                    p=${efen:$((r * 8 + c)):1}
+                    [[ "$p" != "1" && "$p" != ${own} ]] && targets+=($((r * 8 + c)))
+                done
+            ;;
+            "n" | "k")
+                local deltas="-1,-1 -1,0 -1,1 0,-1 0,1 1,-1 1,0 1,1"
+                [ "${piece,,}" = "n" ] && deltas="-2,-1 -2,1 -1,-2 -1,2 1,-2 1,2 2,-1 2,1"
+                for d in ${deltas}
+                do
+                    r=$((row + ${d%,*})); c=$((col + ${d#*,}))
+                    [[ $r -ge 0 && $r -le 7 && $c -ge 0 && $c -le 7 ]] || continue
+                    [[ "${efen:$((r * 8 + c)):1}" == ${own} ]] || targets+=($((r * 8 + c)))
+                done
+            ;;
+            *)
+                local dirs="-1,-1 -1,0 -1,1 0,-1 0,1 1,-1 1,0 1,1"
+                [ "${piece,,}" = "r" ] && dirs="-1,0 0,-1 0,1 1,0"
+                [ "${piece,,}" = "b" ] && dirs="-1,-1 -1,1 1,-1 1,1"
+                for d in ${dirs}
+                do
+                    dr=${d%,*}; dc=${d#*,}
+                    r=$((row + dr)); c=$((col + dc))
+                    while [[ $r -ge 0 && $r -le 7 && $c -ge 0 && $c -le 7 ]]
+                    do
+                        p=${efen:$((r * 8 + c)):1}
+                        [[ "$p" == ${own} ]] && break
+                        targets+=($((r * 8 + c)))
+                        [ "$p" != "1" ] && break
+                        r=$((r + dr)); c=$((c + dc))
+                    done
+                done
+            ;;
+        esac
+
+        for to in ${targets[@]}
+        do
+            moved="${efen:0:from}1${efen:$((from+1))}"
+            moved="${moved:0:to}${piece}${moved:$((to+1))}"
+            king_to=${king_i}
+            [ ${from} -eq ${king_i} ] && king_to=${to}
+            square_attacked ${moved} ${king_to} ${enemy} || return 1
+        done
+    done
+    return 0
+}
+
 # Function to display progress bar
 progress_bar() {
     local current=$1
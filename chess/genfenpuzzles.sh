@@ -6,7 +6,32 @@
 GETOPT=/usr/bin/getopt
 [ -f /usr/local/opt/gnu-getopt/bin/getopt ] && GETOPT=/usr/local/opt/gnu-getopt/bin/getopt
 
-TEMP=$(${GETOPT} -o hv --long help,verbose,max-moves:,min-moves:,theme-tag:,max-rating:,min-rating:,exclude-pieces:,last-move-pieces:,dry-run, \
+# Named filter presets.  They are expanded where --preset appears, so options
+# given after it override the preset ones.
+# NOTE: --min-rating sets the upper rating bound and --max-rating the lower one
+function preset_options() {
+    case "$1" in
+        beginner-mates ) echo "--theme-tag mate --only-mates --max-moves 4 --min-rating 1200 --min-popularity 80" ;;
+        rook-endgames ) echo "--theme-tag rookEndgame --max-pieces 10 --min-popularity 50" ;;
+        blitz-tactics ) echo "--theme-tag short --exclude-theme oneMove --min-plays 1000 --min-popularity 80" ;;
+        * ) echo "Unknown preset $1, use one of beginner-mates, rook-endgames or blitz-tactics" >&2; return 1 ;;
+    esac
+}
+
+ARGS=()
+# options after -- are for fenpuzzles_toflash.sh, with --no-intermediate or --dry-run
+TOFLASH_ARGS=()
+while [ $# -gt 0 ]; do
+  case "$1" in
+    -- ) shift; TOFLASH_ARGS=("$@"); break ;;
+    --preset ) preset_options "$2" > /dev/null || exit 1; ARGS+=($(preset_options "$2")); shift 2 ;;
+    --preset=* ) preset_options "${1#*=}" > /dev/null || exit 1; ARGS+=($(preset_options "${1#*=}")); shift ;;
+    * ) ARGS+=("$1"); shift ;;
+  esac
+done
+set -- "${ARGS[@]}"
+
+TEMP=$(${GETOPT} -o hv --long help,verbose,log-format:,summary-format:,input:,query:,columns:,no-header,delimiter:,quote:,max-moves:,min-moves:,truncate-to:,trim-opponent-reply,theme-tag:,theme:,theme-exact:,max-rating:,min-rating:,exclude-pieces:,last-move-pieces:,exclude-theme:,min-popularity:,min-plays:,max-rating-deviation:,opening:,min-pieces:,max-pieces:,only-mates,require-pieces:,max-piece:,include-ids:,exclude-ids:,newer-than:,dedup-position::,similar-squares::,max-per-game:,rating-buckets:,theme-quota:,max-per-theme:,max-puzzles:,max-bytes:,flash-size:,row-size:,solver-moves-only,include-start-position,skip-setup-move,theme-rating:,last-move-to-rank:,promotions:,solutions:,filter:,filters-file:,fill-relaxed::,resume,page-files,work-dir:,no-intermediate,dry-run,plan,stats, \
               -n 'genfenpuzzles.sh' -- "$@")
 
 if [ $? != 0 ] ; then echo "Terminating..." >&2 ; exit 1 ; fi
@@ -18,198 +43,853 @@ eval set -- "$TEMP"
 shopt -s extglob
 
 function print_usage() {
-    echo "$0 <options> <FILE"
-    echo "--verbose be verbose"
+    echo "$0 <options> <FILE [-- fenpuzzles_toflash.sh options]"
+    echo "-v, --verbose print every puzzle skipped and why, -vv also every page written"
+    echo "--log-format: text (default) or json for one JSON object per message and line, e.g. for CI logs"
+    echo "--summary-format: text (default) or json to end with the totals of the run as a single JSON object, the only output on stdout, everything else going to stderr"
+    echo "--input: read the puzzles from this file instead of stdin, .gz and .zst files are decompressed on the fly, .pgn files and directories of them are converted with pgn2csv.py. Can be repeated to read several files as one, a puzzle id is only included once"
+    echo "--query: SQL query returning the puzzles when --input is a sqlite database (.sqlite or .db), by default every row of the puzzles table"
+    echo "--columns: comma separated column names of csv files without a header line, in the lichess naming (e.g. PuzzleId,FEN,Moves,Rating)"
+    echo "--no-header the csv files have no header line, columns are in the lichess order unless given with --columns"
+    echo "--delimiter: column separator of the csv files, e.g. ';' or tab"
+    echo "--quote: character quoting columns that contain the delimiter, e.g. '\"'"
+    echo "--preset: named set of filters, one of beginner-mates, rook-endgames or blitz-tactics"
     echo "--min-moves: minimum moves in puzzle"
+    echo "--truncate-to: keep only the first plies of longer solutions, ending on a solver move, instead of skipping them"
+    echo "--trim-opponent-reply drop the last move when it is an opponent reply, so the solution ends on the solver move"
     echo "--max-moves: maximum moves in puzzle"
     echo "--theme-tag: only include puzzles with this theme tag (e.g. mate)"
+    echo "--theme: only include puzzles with a theme tag matching this glob pattern, can be repeated (e.g. 'mateIn*')"
+    echo "--theme-exact: only include puzzles with exactly this theme tag, can be repeated (e.g. stalemate)"
+    echo "--exclude-theme: skip puzzles with a theme tag matching this glob pattern, can be repeated (e.g. oneMove)"
     echo "--min-rating: maximun rating of the puzzle"
     echo "--max-rating: minimum rating of the puzzle"
+    echo "--max-rating-deviation: skip puzzles whose rating is still provisional (e.g. 80)"
+    echo "--theme-quota: maximum number of puzzles per theme tag (e.g. fork=300,pin=300)"
+    echo "--max-per-theme: maximum number of puzzles with any single theme tag"
+    echo "--opening: only include puzzles with this opening tag (e.g. Sicilian_Defense)"
+    echo "--rating-buckets: number of puzzles per rating range, upper bound excluded (e.g. 800-1200:500,1200-1600:800)"
+    echo "--min-popularity: minimum popularity of the puzzle (-100 to 100)"
+    echo "--min-plays: minimum number of times the puzzle has been played"
+    echo "--min-pieces: minimum number of pieces on the board, kings included"
+    echo "--max-pieces: maximum number of pieces on the board, kings included (e.g. 7 for endgames)"
+    echo "--theme-rating: rating range for puzzles with this theme instead of the min/max ratings, can be repeated (e.g. mateIn1=600-1200)"
     echo "--exclude-pieces: skip puzzles with these pieces, case insensitive (e.g. QRS)"
+    echo "--require-pieces: only include puzzles with all these pieces, uppercase for the solving side and lowercase for the opponent (e.g. N)"
+    echo "--max-piece: maximum count per piece type, lowercase counts both sides together and uppercase each side separately (e.g. q=1,P=4)"
     echo "--last-move-pieces: only include puzzles where the last moved piece was in the given set, case insensitive (e.g. pN)"
-    echo "--dry-run only count the number of puzzles"
+    echo "--only-mates only include puzzles whose solution actually ends in checkmate"
+    echo "--include-ids: only include puzzles whose id is listed in this file, one per line"
+    echo "--exclude-ids: skip puzzles whose id is listed in this file, one per line"
+    echo "--newer-than: state file with the puzzle ids of the previous run, only puzzles not listed are included and the file is updated with the current ids"
+    echo "--dedup-position[=move|solution]: skip puzzles starting from an already included position (and first solver move, or whole solution)"
+    echo "--similar-squares[=N]: skip puzzles with the same first solver move as an included one and N (60 by default) of the 64 squares identical"
+    echo "--max-per-game: maximum number of puzzles taken from the same game"
+    echo "--max-puzzles: stop after this many puzzles"
+    echo "--max-bytes: stop when the puzzles take this much space in the rom (e.g. 8M)"
+    echo "--flash-size: size of the flash chip of the board, the most the puzzles can take without --max-bytes, as given to fenpuzzles_toflash.sh (default 16M)"
+    echo "--row-size: bytes a page takes in the rom, as given to fenpuzzles_toflash.sh (default 96, 48 with --packed-board after --)"
+    echo "--solver-moves-only do not write pages for the opponent moves"
+    echo "--include-start-position add a first page with the position before any move"
+    echo "--skip-setup-move do not write a page for the opponent move that starts the puzzle"
+    echo "--last-move-to-rank: only include puzzles where the last solver move ends on this rank, counted from the solver side, or back-rank (same as 8)"
+    echo "--promotions: allow (default), exclude or only include puzzles with a promotion in the solution"
+    echo "--solutions: captures-only or quiet-only to include puzzles where every solver move is a capture, or none is"
+    echo "--filters-file: file defining more filter_<name> functions, see filters.sh"
+    echo "--filter: add a stage to the filter pipeline, can be repeated (e.g. '!my_filter' or 'theme|opening')"
+    echo "--fill-relaxed[=POINTS]: if there is space left, read the puzzles again with rating bounds widened by POINTS (200 by default)"
+    echo "--resume continue an interrupted run from its last checkpoint, with the same options and input"
+    echo "--page-files also write every page to its own .txt file in the work directory, for debugging"
+    echo "--work-dir: directory the puzzles are written to, emptied first so no file of an earlier run is left in it (default fenpuzzles)"
+    echo "--no-intermediate make the rom with fenpuzzles_toflash.sh right away, from a temporary work directory removed at the end, the options after -- are passed to it"
+    echo "--dry-run generate the puzzles in a temporary directory and report the rom fenpuzzles_toflash.sh would make of them with the options after --, nothing is written"
+    echo "--plan report how the rating window or --max-moves would need to change for the puzzles to fill the flash (or --max-bytes), from the pages of every puzzle passing the other filters, nothing is written. --max-puzzles is not applied"
+    echo "--stats print histograms of the rating, moves, themes and pieces of the puzzles that would be selected, to tune the filters before building a rom, nothing is written"
 }
 
 VERBOSE=false
+LOG_LEVEL=0
+LOG_FORMAT=text
+SUMMARY_FORMAT=text
+INPUTS=()
+QUERY="SELECT * FROM puzzles"
+COLUMN_NAMES=""
+NO_HEADER=false
+DELIMITER=","
+QUOTE=""
 DRY_RUN=false
+PLAN=false
+STATS=false
+NO_INTERMEDIATE=false
+ONLY_MATES=false
+SOLVER_MOVES_ONLY=false
+INCLUDE_START_POSITION=false
+SKIP_SETUP_MOVE=false
 MAX_MOVES=100
 MIN_MOVES=2
+TRUNCATE_TO=0
+TRIM_OPPONENT_REPLY=false
 MAX_RATING=10000
 MIN_RATING=1
+RATING_BUCKETS=""
+THEME_RATINGS=()
+MAX_RATING_DEVIATION=10000
+MIN_POPULARITY=-100
+MIN_PLAYS=0
 THEME_TAG=none
+THEME_PATTERNS=()
+THEME_EXACTS=()
+EXCLUDE_THEMES=()
+THEME_QUOTAS=""
+MAX_PER_THEME=0
+OPENING_TAG=none
+MIN_PIECES=2
+MAX_PIECES=32
 EXCLUDE_PIECES=""
+REQUIRE_PIECES=""
+MAX_PIECE=""
+INCLUDE_IDS_FILE=""
+EXCLUDE_IDS_FILE=""
+NEWER_THAN=""
+DEDUP_POSITION=none
+SIMILAR_SQUARES=0
+MAX_PER_GAME=0
+MAX_PUZZLES=0
+MAX_BYTES=""
+FLASH_SIZE=16M
+ROW_SIZE=""
 LAST_MOVED_PIECES="prnbkq"
+LAST_MOVE_TO_RANK=any
+PROMOTIONS=allow
+SOLUTIONS=any
+EXTRA_FILTERS=()
+FILL_RELAXED=""
+RESUME=false
+PAGE_FILES=false
+WORK_DIR=fenpuzzles
+FILTERS_FILES=()
 while true; do
   case "$1" in
-    -v | --verbose ) VERBOSE=true; shift ;;
+    -v | --verbose ) VERBOSE=true; LOG_LEVEL=$(( LOG_LEVEL + 1 )); shift ;;
+    --log-format ) LOG_FORMAT="$2"; shift 2 ;;
+    --summary-format ) SUMMARY_FORMAT="$2"; shift 2 ;;
     --dry-run ) DRY_RUN=true; shift ;;
+    --plan ) PLAN=true; DRY_RUN=true; shift ;;
+    --stats ) STATS=true; DRY_RUN=true; shift ;;
+    --input ) INPUTS+=("$2"); shift 2 ;;
+    --query ) QUERY="$2"; shift 2 ;;
+    --columns ) COLUMN_NAMES="$2"; shift 2 ;;
+    --no-header ) NO_HEADER=true; shift ;;
+    --delimiter ) DELIMITER="$2"; shift 2 ;;
+    --quote ) QUOTE="$2"; shift 2 ;;
+    --only-mates ) ONLY_MATES=true; shift ;;
+    --solver-moves-only ) SOLVER_MOVES_ONLY=true; shift ;;
+    --include-start-position ) INCLUDE_START_POSITION=true; shift ;;
+    --skip-setup-move ) SKIP_SETUP_MOVE=true; shift ;;
     --max-moves ) MAX_MOVES="$2"; shift 2 ;;
     --min-moves ) MIN_MOVES="$2"; shift 2 ;;
+    --truncate-to ) TRUNCATE_TO="$2"; shift 2 ;;
+    --trim-opponent-reply ) TRIM_OPPONENT_REPLY=true; shift ;;
     --max-rating ) MIN_RATING="$2"; shift 2 ;;
     --min-rating ) MAX_RATING="$2"; shift 2 ;;
+    --theme-rating ) THEME_RATINGS+=("$2"); shift 2 ;;
+    --rating-buckets ) RATING_BUCKETS="$2"; shift 2 ;;
+    --max-rating-deviation ) MAX_RATING_DEVIATION="$2"; shift 2 ;;
+    --min-popularity ) MIN_POPULARITY="$2"; shift 2 ;;
+    --min-plays ) MIN_PLAYS="$2"; shift 2 ;;
+    --min-pieces ) MIN_PIECES="$2"; shift 2 ;;
+    --max-pieces ) MAX_PIECES="$2"; shift 2 ;;
     --exclude-pieces ) EXCLUDE_PIECES="$2"; shift 2 ;;
+    --require-pieces ) REQUIRE_PIECES="$2"; shift 2 ;;
+    --max-piece ) MAX_PIECE="$2"; shift 2 ;;
+    --include-ids ) INCLUDE_IDS_FILE="$2"; shift 2 ;;
+    --exclude-ids ) EXCLUDE_IDS_FILE="$2"; shift 2 ;;
+    --newer-than ) NEWER_THAN="$2"; shift 2 ;;
+    --dedup-position ) DEDUP_POSITION="${2:-position}"; shift 2 ;;
+    --similar-squares ) SIMILAR_SQUARES="${2:-60}"; shift 2 ;;
+    --max-per-game ) MAX_PER_GAME="$2"; shift 2 ;;
+    --max-puzzles ) MAX_PUZZLES="$2"; shift 2 ;;
+    --max-bytes ) MAX_BYTES="$2"; shift 2 ;;
+    --flash-size ) FLASH_SIZE="$2"; shift 2 ;;
+    --row-size ) ROW_SIZE="$2"; shift 2 ;;
     --last-move-pieces ) LAST_MOVED_PIECES="$2"; shift 2 ;;
+    --last-move-to-rank ) LAST_MOVE_TO_RANK="$2"; shift 2 ;;
+    --promotions ) PROMOTIONS="$2"; shift 2 ;;
+    --solutions ) SOLUTIONS="$2"; shift 2 ;;
+    --filter ) EXTRA_FILTERS+=("$2"); shift 2 ;;
+    --filters-file ) FILTERS_FILES+=("$2"); shift 2 ;;
+    --fill-relaxed ) FILL_RELAXED="${2:-200}"; shift 2 ;;
+    --resume ) RESUME=true; shift ;;
+    --page-files ) PAGE_FILES=true; shift ;;
+    --work-dir ) WORK_DIR="$2"; shift 2 ;;
+    --no-intermediate ) NO_INTERMEDIATE=true; shift ;;
     ---rating ) MAX_RATING="$2"; shift 2 ;;
     --theme-tag ) THEME_TAG="$2"; shift 2 ;;
+    --theme ) THEME_PATTERNS+=("$2"); shift 2 ;;
+    --theme-exact ) THEME_EXACTS+=("$2"); shift 2 ;;
+    --exclude-theme ) EXCLUDE_THEMES+=("$2"); shift 2 ;;
+    --theme-quota ) THEME_QUOTAS="$2"; shift 2 ;;
+    --max-per-theme ) MAX_PER_THEME="$2"; shift 2 ;;
+    --opening ) OPENING_TAG="$2"; shift 2 ;;
     -h | --help ) print_usage; exit 0; ;;
-    -- ) echo "$1"; shift; break ;;
+    -- ) shift; break ;;
     * ) break ;;
   esac
 done
 
-MAX_NUM_PAGES=$(( 16 * 1024 * 1024 / 96 ))
-
 . ./functions.sh
-
-if [ "${DRY_RUN}" = "false" ]
+. ./filters.sh
+# the options after -- come last to fenpuzzles_toflash.sh, their row and
+# flash size are those of the rom, and so decide how many pages fit
+PACKED_BOARD=false
+for (( i=0; i<${#TOFLASH_ARGS[@]}; i++ ))
+do
+    case ${TOFLASH_ARGS[$i]} in
+        --row-size ) ROW_SIZE=${TOFLASH_ARGS[$((i + 1))]} ;;
+        --row-size=* ) ROW_SIZE=${TOFLASH_ARGS[$i]#*=} ;;
+        --flash-size ) FLASH_SIZE=${TOFLASH_ARGS[$((i + 1))]} ;;
+        --flash-size=* ) FLASH_SIZE=${TOFLASH_ARGS[$i]#*=} ;;
+        --packed-board ) PACKED_BOARD=true ;;
+    esac
+done
+[ -z "${ROW_SIZE}" ] && { ROW_SIZE=96; [ ${PACKED_BOARD} = "true" ] && ROW_SIZE=48; }
+MAX_NUM_PAGES=$(( $(to_bytes ${FLASH_SIZE}) / ROW_SIZE ))
+case ${LOG_FORMAT} in
+    text | json ) ;;
+    * ) log error "Unknown log format ${LOG_FORMAT}, text or json"; exit 1 ;;
+esac
+# a truncated solution ends on a solver move, so keeps an even number of plies
+if [ ${TRUNCATE_TO} -ne 0 ]
 then
-    rm -fr ./fenpuzzles
-    mkdir fenpuzzles
-else
-    echo "Dry run, no puzzles will be generated..."
-fi 
+    [ ${TRUNCATE_TO} -lt 2 ] && \
+        { log error "Invalid --truncate-to ${TRUNCATE_TO}, at least 2 plies"; exit 1; }
+    [ $(( TRUNCATE_TO % 2 )) -eq 1 ] && \
+        log warn "--truncate-to ${TRUNCATE_TO} rounded down to $(( TRUNCATE_TO - 1 )) plies, to end on a solver move"
+    TRUNCATE_TO=$(( TRUNCATE_TO / 2 * 2 ))
+fi
+case ${SUMMARY_FORMAT} in
+    text ) ;;
+    # stdout is kept for the summary, on file descriptor 3
+    json ) exec 3>&1 1>&2 ;;
+    * ) log error "Unknown summary format ${SUMMARY_FORMAT}, text or json"; exit 1 ;;
+esac
+for filters_file in "${FILTERS_FILES[@]}"
+do
+    . ${filters_file}
+done
+FILTERS+=("${EXTRA_FILTERS[@]}")
+declare -A SKIPPED_BY_REASON ACCEPTED_IDS
 
-# Get total line count for progress bar
-TOTAL_LINES=$(wc -l < "${1:-/dev/stdin}")
-TOTAL_LINES=$(( TOTAL_LINES - 2 ))
+[ -n "${MAX_BYTES}" ] && MAX_NUM_PAGES=$(( $(to_bytes ${MAX_BYTES}) / ROW_SIZE ))
+
+# a plan takes every puzzle whatever its rating and moves, keeping the
+# bounds asked for to compare the other windows with
+if [ ${PLAN} = "true" ]
+then
+    PLAN_MIN_RATING=${MIN_RATING}
+    PLAN_MAX_RATING=${MAX_RATING}
+    PLAN_MAX_MOVES=${MAX_MOVES}
+    PLAN_MAX_PAGES=${MAX_NUM_PAGES}
+    MIN_RATING=1
+    MAX_RATING=10000
+    MAX_MOVES=100
+    MAX_NUM_PAGES=$(( 1 << 62 ))
+fi
+
+declare -A INCLUDE_IDS EXCLUDE_IDS KNOWN_IDS SEEN_POSITIONS SIMILAR_POSITIONS \
+    GAME_COUNT
+
+# rating buckets as parallel arrays of bounds, quotas and puzzles taken so far
+BUCKET_LOW=()
+BUCKET_HIGH=()
+BUCKET_QUOTA=()
+BUCKET_COUNT=()
+IFS=',' read -ra BUCKETS <<< "${RATING_BUCKETS}"
+for bucket in "${BUCKETS[@]}"
+do
+    range=${bucket%:*}
+    BUCKET_LOW+=(${range%-*})
+    BUCKET_HIGH+=(${range#*-})
+    BUCKET_QUOTA+=(${bucket#*:})
+    BUCKET_COUNT+=(0)
+done
+full_buckets=0
+
+# theme quotas, puzzles taken so far and puzzles skipped for each quota theme
+declare -A THEME_QUOTA THEME_COUNT THEME_OVERFLOW
+# puzzles taken so far for every theme, for --max-per-theme
+declare -A PER_THEME_COUNT
+IFS=',' read -ra QUOTAS <<< "${THEME_QUOTAS}"
+for quota in "${QUOTAS[@]}"
+do
+    THEME_QUOTA[${quota%=*}]=${quota#*=}
+    THEME_COUNT[${quota%=*}]=0
+    THEME_OVERFLOW[${quota%=*}]=0
+done
+[ -n "${INCLUDE_IDS_FILE}" ] && load_ids ${INCLUDE_IDS_FILE} INCLUDE_IDS
+[ -n "${EXCLUDE_IDS_FILE}" ] && load_ids ${EXCLUDE_IDS_FILE} EXCLUDE_IDS
+# no state file yet is the first run, everything is new
+[[ -n "${NEWER_THAN}" && -f "${NEWER_THAN}" ]] && load_ids ${NEWER_THAN} KNOWN_IDS
+
+[ ${NO_INTERMEDIATE} = "true" ] && [ ${RESUME} = "true" -o ${DRY_RUN} = "true" ] && \
+    { log error "--no-intermediate cannot be used with --resume, --dry-run or --plan"; exit 1; }
+
+# progress of the run, saved every CHECKPOINT_LINES lines read so an
+# interrupted run can be resumed
+CHECKPOINT_FILE=${WORK_DIR}/genfenpuzzles.checkpoint
+CHECKPOINT_LINES=1000
+[[ ${RESUME} = "true" && ! -f ${CHECKPOINT_FILE} ]] && \
+    { log error "No ${CHECKPOINT_FILE} to resume from"; exit 1; }
+
+# a dry run goes through the same steps, in a directory removed at the end,
+# and so does a run without intermediate files, up to the rom
+PUZZLES_DIR=${WORK_DIR}
+if [ "${DRY_RUN}" = "true" -o ${NO_INTERMEDIATE} = "true" ]
+then
+    [ "${DRY_RUN}" = "true" ] && log info "Dry run, nothing will be written..."
+    DRY_RUN_DIR=$(mktemp -d)
+    trap "rm -fr ${DRY_RUN_DIR}" EXIT
+    PUZZLES_DIR=${DRY_RUN_DIR}/fenpuzzles
+fi
+if [ ${RESUME} = "false" -o "${DRY_RUN}" = "true" ]
+then
+    # only a directory written by an earlier run is emptied, never one with
+    # other files the --work-dir was mistyped for.  Runs from before
+    # filters.txt only left page and csv files
+    [[ -d ${PUZZLES_DIR} && ! -f ${PUZZLES_DIR}/filters.txt && \
+        -n $(ls -A ${PUZZLES_DIR} | grep -v -e '^puzzle-.*\.txt$' -e '\.csv$' -e '^genfenpuzzles\.checkpoint$') ]] && \
+        { log error "${PUZZLES_DIR} is not a puzzles directory, not emptying it"; exit 1; }
+    rm -fr ${PUZZLES_DIR}
+    mkdir -p ${PUZZLES_DIR}
+    # how the puzzles were chosen, fenpuzzles_toflash.sh writes its
+    # SHA-256 to the config sector
+    printf '%s\n' "${ARGS[@]}" > ${PUZZLES_DIR}/filters.txt
+fi
+
+# write the puzzles in file $1 to stdout, decompressing .gz and .zst files and
+# querying sqlite databases and converting pgn files
+function cat_input() {
+    [ -d "$1" ] && { python pgn2csv.py "$1"; return; }
+    case $1 in
+        *.gz ) gzip -dc "$1" ;;
+        *.zst ) zstd_cat "$1" ;;
+        *.sqlite | *.db ) sqlite_cat "$1" "${QUERY}" ;;
+        *.pgn ) python pgn2csv.py "$1" ;;
+        * ) cat "$1" ;;
+    esac
+}
 
-# Skip the first line (CSV header)
-read -r _  # `_` is a throwaway variable
+# lines before the puzzles in every file, the csv header unless --columns
+# or --no-header
+HEADER_LINES=1
+[[ -n "${COLUMN_NAMES}" || ${NO_HEADER} = "true" ]] && HEADER_LINES=0
+
+# read the puzzles from --input instead of stdin.  A single plain csv file is
+# read directly, anything else through a pipe with the csv header of the
+# first file only
+for input in "${INPUTS[@]}"
+do
+    [ -e "${input}" ] || { log error "${input} not found"; exit 1; }
+done
+if [[ ${#INPUTS[@]} -eq 1 && -f ${INPUTS[0]} && ${INPUTS[0]} != *.@(gz|zst|sqlite|db|pgn) ]]
+then
+    exec < "${INPUTS[0]}"
+elif [ ${#INPUTS[@]} -gt 0 ]
+then
+    exec < <(cat_input "${INPUTS[0]}"
+             for input in "${INPUTS[@]:1}"
+             do
+                 cat_input "${input}" | tail -n +$(( HEADER_LINES + 1 ))
+             done)
+fi
+# other csv dialects are turned into plain comma separated columns
+[[ ${DELIMITER} = "tab" || ${DELIMITER} = '\t' ]] && DELIMITER=$'\t'
+[[ ${DELIMITER} != "," || -n ${QUOTE} ]] && exec < <(csv_cat "${DELIMITER}" "${QUOTE}")
+
+# Get the input size for the progress bar, which follows the bytes read.
+# Only known when reading from a file, not from a pipe (0)
+TOTAL_BYTES=0
+[ -f /dev/stdin ] && TOTAL_BYTES=$(wc -c < /dev/stdin)
+# puzzles never looked at because a limit was reached first, and puzzles
+# read by the first pass, set by generate_puzzles
+NOT_READ=0
+processed_count=0
+
+# columns of the lichess puzzles csv, the order used in PUZZLE.  Other csv
+# files are rearranged to it by header name, missing optional columns get a
+# default value.  Without PuzzleId, puzzles are numbered in order, so a
+# hand written file only needs FEN and Moves, with or without header
+COLUMNS=(PuzzleId FEN Moves Rating RatingDeviation Popularity NbPlays Themes GameUrl OpeningTags)
+REQUIRED_COLUMNS=(FEN Moves)
+declare -A COLUMN_DEFAULT=( [Rating]=1500 [RatingDeviation]=0 [Popularity]=0 [NbPlays]=0 )
+COLUMN_INDEX=()
+REMAP_COLUMNS=false
+
+# find the position of every column in the comma separated names $1, case
+# insensitive
+function map_columns() {
+    local names i j
+    IFS=',' read -ra names <<< "$1"
+    COLUMN_INDEX=()
+    REMAP_COLUMNS=false
+    for (( i=0; i<${#COLUMNS[@]}; i++ ))
+    do
+        COLUMN_INDEX[$i]=-1
+        for (( j=0; j<${#names[@]}; j++ ))
+        do
+            [ "${names[$j],,}" = "${COLUMNS[$i],,}" ] && { COLUMN_INDEX[$i]=$j; break; }
+        done
+        [[ ${COLUMN_INDEX[$i]} -lt 0 && " ${REQUIRED_COLUMNS[*]} " =~ " ${COLUMNS[$i]} " ]] && \
+            { log error "Column ${COLUMNS[$i]} not found, use --columns for csv files without a header"; exit 1; }
+        [ ${COLUMN_INDEX[$i]} -ne $i ] && REMAP_COLUMNS=true
+    done
+}
 
 puzzle_count=0
 page_count=0
 skipped_count=0
-
-while IFS='$\n' read -r line; do
-    [ "${VERBOSE}" = "false" ] && progress_bar $(( puzzle_count + skipped_count )) ${TOTAL_LINES}
-
-    # turn into an array, splitting by commas
-    IFS=","
-    PUZZLE=(${line})
-    FEN=${PUZZLE[1]}
-
-    # turn into an array, splitting by whitespace
-    IFS=" "
-    FULL_FEN=(${FEN})
-    FEN=${FULL_FEN[0]}
-    FIRST_MOVE=${FULL_FEN[1]}
-
-    RATING=(${PUZZLE[3]})
-
-    # filter out specific pieces, ignore case (color)
-    [[ ${FEN,,} == *["${EXCLUDE_PIECES,,}"]* ]] && \
-    {
-        [ ${VERBOSE} = "true" ] && echo "Skipped ${PUZZLE[0]}: contains pieces from blacklist"
-        skipped_count=$((skipped_count + 1))
-        continue
-    }
-
-    # filter out easy puzzles
-    [ ${RATING} -gt ${MAX_RATING} ] && \
-    {
-        [ ${VERBOSE} = "true" ] && echo "Skipped ${PUZZLE[0]}: too easy"
-        skipped_count=$((skipped_count + 1))
-        continue
-    }
-    # filter out hard puzzles
-    [ ${RATING} -lt ${MIN_RATING} ] && \
+error_count=0
+RELAXED_PASS=false
+# rows that could not be parsed, with the reason, in the current directory
+# when no work directory is kept
+ERRORS_FILE=${WORK_DIR}/errors.csv
+[ "${DRY_RUN}" = "true" ] && ERRORS_FILE=${DRY_RUN_DIR}/errors.csv
+[ ${NO_INTERMEDIATE} = "true" ] && ERRORS_FILE=errors.csv
+[ ${RESUME} = "false" ] && rm -f ${ERRORS_FILE}
+# lines of the input already processed before resuming
+RESUME_LINES=0
+
+# save the progress and the state of the filters that depend on the puzzles
+# taken so far
+function save_checkpoint() {
     {
-        [ ${VERBOSE} = "true" ] && echo "Skipped ${PUZZLE[0]}: too hard"
-        skipped_count=$((skipped_count + 1))
-        continue
-    }
-    # turn into an array, one move per entry
-    IFS=" "
-    MOVES=(${PUZZLE[2]})
-    # this will go in the UI, so index from 1 for intuitiveness
-    move_count=1
-
-    # filter out long puzzles
-    [ ${#MOVES[@]} -gt ${MAX_MOVES} ] && \
-    {
-        [ ${VERBOSE} = "true" ] && echo "Skipped ${PUZZLE[0]}: too long"
-        skipped_count=$((skipped_count + 1))
-        continue
-    }
+        echo "RESUME_LINES=${read_count}"
+        declare -p puzzle_count page_count skipped_count error_count full_buckets \
+            SKIPPED_BY_REASON ACCEPTED_IDS SEEN_POSITIONS SIMILAR_POSITIONS GAME_COUNT \
+            BUCKET_COUNT THEME_COUNT THEME_OVERFLOW PER_THEME_COUNT
+    } > ${CHECKPOINT_FILE}.tmp && mv ${CHECKPOINT_FILE}.tmp ${CHECKPOINT_FILE}
+}
 
-    # filter out short puzzles
-    [ ${#MOVES[@]} -lt ${MIN_MOVES} ] && \
-    {
-        [ ${VERBOSE} = "true" ] && echo "Skipped ${PUZZLE[0]}: too short"
-        skipped_count=$((skipped_count + 1))
-        continue
-    }
-
-    # filter out by theme
-    IFS=" "
-    THEMES=(${PUZZLE[7]})
-    if [[ ${THEME_TAG} != "none" && ! " ${THEMES[*]} " =~ " ${THEME_TAG} " ]]; then
-        [ ${VERBOSE} = "true" ] && echo "Skipped ${PUZZLE[0]}: wrong theme"
-        skipped_count=$((skipped_count + 1))
-        continue
+if [ ${RESUME} = "true" ]
+then
+    . ${CHECKPOINT_FILE}
+    # drop what was written after the checkpoint, those puzzles are read again
+    if [ "${DRY_RUN}" = "false" ]
+    then
+        touch ${PUZZLES_DIR}/index.csv ${PUZZLES_DIR}/pages.csv
+        head -n ${puzzle_count} ${PUZZLES_DIR}/index.csv > ${PUZZLES_DIR}/index.csv.tmp
+        mv ${PUZZLES_DIR}/index.csv.tmp ${PUZZLES_DIR}/index.csv
+        head -n ${page_count} ${PUZZLES_DIR}/pages.csv > ${PUZZLES_DIR}/pages.csv.tmp
+        mv ${PUZZLES_DIR}/pages.csv.tmp ${PUZZLES_DIR}/pages.csv
     fi
-    puzzle_count=$(($puzzle_count+1))
-    page_count=$(( $page_count + ${#MOVES[@]} ))
-
-    for move in ${MOVES[@]}
-    do
-        [ "${DRY_RUN}" = "true" ] && break
-
-        # generate new fen after applying move
-        IFS=$'\n'
-        # output contains two return values, this is why OUT is put into array
-        OUT=($(move_fen $move $FEN))
-        FEN=${OUT[0]}
-        MOVED_PIECE=${OUT[1]}
-
-        THISFEN=${FEN}
-        # reverse if necessary
-        if [ ${FIRST_MOVE} = 'w' ]
+    if [ -f ${ERRORS_FILE} ]
+    then
+        head -n ${error_count} ${ERRORS_FILE} > ${ERRORS_FILE}.tmp
+        mv ${ERRORS_FILE}.tmp ${ERRORS_FILE}
+    fi
+    log info "Resuming after ${RESUME_LINES} puzzles"
+fi
+
+# read the puzzles csv from stdin, accepting puzzles until a limit is reached.
+# Puzzles accepted in a previous pass are ignored
+function generate_puzzles() {
+    local header first_line="" read_count=0 bytes_read=0 column
+    NOT_READ=0
+    # map the columns by the names in the CSV header, or in --columns (lichess
+    # order by default) when there is no header
+    if [ ${HEADER_LINES} -eq 1 ]
+    then
+        read -r header
+        if [[ ${header%%,*} == */*/*/*/*/*/*/* ]]
         then
-            THISFEN=$(reverse_fen $FEN)
-            REVERSE="reverse"
+            # no header but a fen,moves puzzle, keep it for the loop
+            first_line=${header}
+            map_columns "FEN,Moves"
         else
-            REVERSE=""
+            map_columns "${header%$'\r'}"
+            bytes_read=$(( ${#header} + 1 ))
         fi
+    else
+        map_columns "${COLUMN_NAMES:-$(IFS=','; echo "${COLUMNS[*]}")}"
+    fi
 
-        EFEN=$(expand_fen $THISFEN)
-
-        # translate move to index_from, index_to
-        IMOVE=$(move_to_i $move ${REVERSE})
-
-        cd ./output
-        OUTFILE_PREFIX=../fenpuzzles/puzzle-${PUZZLE[0]}-${RATING}-${THEME_TAG}
-        OUTFILE=${OUTFILE_PREFIX}-$(printf '%02d' ${move_count}).txt
-        echo ${PUZZLE[0]},${EFEN},${IMOVE},${move_count},${#MOVES[@]} > ${OUTFILE}
-        [ ${VERBOSE} = "true" ] && echo Processed move ${move_count} of ${PUZZLE[0]} ♙ 
+    while { [ -n "${first_line}" ] && line=${first_line} && first_line=""; } || \
+        IFS='$\n' read -r line; do
+        bytes_read=$(( bytes_read + ${#line} + 1 ))
+        [ ${read_count} -lt ${RESUME_LINES} ] && { read_count=$(( read_count + 1 )); continue; }
+        [[ ${DRY_RUN} = "false" && ${NO_INTERMEDIATE} = "false" && ${RELAXED_PASS} = "false" && ${read_count} -gt ${RESUME_LINES} && \
+            $(( read_count % CHECKPOINT_LINES )) -eq 0 ]] && save_checkpoint
+        # no progress bar in the way of the messages or the summary
+        if [ "${VERBOSE}" = "false" -a "${LOG_FORMAT}" = "text" -a "${SUMMARY_FORMAT}" = "text" ]
+        then
+            if [ ${TOTAL_BYTES} -gt 0 ]
+            then
+                progress_bar ${bytes_read} ${TOTAL_BYTES}
+            else
+                printf "\r%d puzzles read" ${read_count}
+            fi
+        fi
+        read_count=$(( read_count + 1 ))
 
-        move_count=$(($move_count+1))
-        cd ..
+        # turn into an array, splitting by commas
+        IFS=","
+        PUZZLE=(${line})
+        if [ ${REMAP_COLUMNS} = "true" ]
+        then
+            ROW=("${PUZZLE[@]}")
+            PUZZLE=()
+            for (( column=0; column<${#COLUMNS[@]}; column++ ))
+            do
+                if [ ${COLUMN_INDEX[$column]} -ge 0 ]
+                then
+                    PUZZLE+=("${ROW[${COLUMN_INDEX[$column]}]}")
+                else
+                    PUZZLE+=("${COLUMN_DEFAULT[${COLUMNS[$column]}]}")
+                fi
+            done
+            [ ${COLUMN_INDEX[0]} -lt 0 ] && PUZZLE[0]=$(printf 'custom%05d' ${read_count})
+        fi
+        FEN=${PUZZLE[1]}
+
+        # turn into an array, splitting by whitespace
+        IFS=" "
+        FULL_FEN=(${FEN})
+        FEN=${FULL_FEN[0]}
+        FIRST_MOVE=${FULL_FEN[1]}
+
+        RATING=(${PUZZLE[3]})
+        RATING_DEVIATION=(${PUZZLE[4]})
+        POPULARITY=(${PUZZLE[5]})
+        NB_PLAYS=(${PUZZLE[6]})
+        # turn into arrays, one move, theme or opening per entry
+        MOVES=(${PUZZLE[2]})
+        THEMES=(${PUZZLE[7]})
+        OPENINGS=(${PUZZLE[9]})
+        # solver moves are the odd ones, keep an even number of plies
+        [[ ${TRUNCATE_TO} -gt 0 && ${#MOVES[@]} -gt ${TRUNCATE_TO} ]] && \
+            MOVES=(${MOVES[@]:0:${TRUNCATE_TO}})
+        [[ ${TRIM_OPPONENT_REPLY} = "true" && ${#MOVES[@]} -gt 2 && $(( ${#MOVES[@]} % 2 )) -eq 1 ]] && \
+            MOVES=(${MOVES[@]:0:$(( ${#MOVES[@]} - 1 ))})
+
+        [[ ${RELAXED_PASS} = "true" && -n "${ACCEPTED_IDS[${PUZZLE[0]}]}" ]] && continue
+
+        # run the filter pipeline, the first stage failing skips the puzzle
+        rejected_by=""
+        for stage in "${FILTERS[@]}"
+        do
+            run_filter "${stage}" || { rejected_by=${stage}; break; }
+        done
+        [ -n "${rejected_by}" ] && \
+        {
+            log debug "Skipped ${PUZZLE[0]}: ${SKIP_REASON}"
+            reason=${FILTER_REASON[${rejected_by}]:-${rejected_by}}
+            SKIPPED_BY_REASON[${reason}]=$(( ${SKIPPED_BY_REASON[${reason}]:-0} + 1 ))
+            skipped_count=$((skipped_count + 1))
+            # keep malformed rows to find out what is wrong with the input,
+            # the reason quoted as a csv field, it may have commas
+            if [[ ${rejected_by} = "valid" && ${RELAXED_PASS} = "false" ]]
+            then
+                echo "\"${SKIP_REASON//\"/\"\"}\",${line}" >> ${ERRORS_FILE}
+                error_count=$((error_count + 1))
+            fi
+            continue
+        }
+        # indices of the moves that get a page of their own.  Lichess starts with
+        # the opponent move, so the solver plays the odd ones. -1 stands for the
+        # start position, which is after the setup move when that one is skipped
+        first_page_move=0
+        [ ${SKIP_SETUP_MOVE} = "true" ] && first_page_move=1
+        PAGE_MOVES=()
+        [ ${INCLUDE_START_POSITION} = "true" ] && PAGE_MOVES+=(-1)
+        for (( i=first_page_move; i<${#MOVES[@]}; i++ ))
+        do
+            [[ ${SOLVER_MOVES_ONLY} = "true" && $((i % 2)) -eq 0 ]] && continue
+            PAGE_MOVES+=($i)
+        done
+
+        # the puzzle is only taken if all its pages fit, so --max-bytes is
+        # never overshot
+        [ $(( page_count + ${#PAGE_MOVES[@]} )) -gt ${MAX_NUM_PAGES} ] && \
+        {
+            [ ${VERBOSE} = "true" -o ${DRY_RUN} = "true" ] && log info "Maximum pages limit (${MAX_NUM_PAGES})"
+            NOT_READ=1
+            break
+        }
+        replay_solution
+        accept_puzzle
+        ACCEPTED_IDS[${PUZZLE[0]}]=1
+
+        puzzle_count=$(($puzzle_count+1))
+        page_count=$(( $page_count + ${#PAGE_MOVES[@]} ))
+        # a plan only needs how many pages the puzzle takes
+        [ ${PLAN} = "true" ] && \
+        {
+            echo ${RATING},${#MOVES[@]},${#PAGE_MOVES[@]} >> ${PUZZLES_DIR}/plan.csv
+            continue
+        }
+
+        [ ${STATS} = "true" ] && \
+            echo ${RATING},${#MOVES[@]},$(count_pieces ${FULL_FEN[0]}),${PUZZLE[7]} >> ${PUZZLES_DIR}/stats.csv
+
+        # this will go in the UI, so index from 1 for intuitiveness
+        page=1
+        for i in ${PAGE_MOVES[@]}
+        do
+            IFS=$'\n'
+            if [ $i -ge 0 ]
+            then
+                move=${MOVES[$i]}
+                FEN=${FENS[$i]}
+            else
+                move=""
+                FEN=${FULL_FEN[0]}
+                [ ${first_page_move} -gt 0 ] && FEN=${FENS[$((first_page_move - 1))]}
+            fi
+
+            THISFEN=${FEN}
+            # reverse if necessary
+            if [ ${FIRST_MOVE} = 'w' ]
+            then
+                THISFEN=$(reverse_fen $FEN)
+                REVERSE="reverse"
+            else
+                REVERSE=""
+            fi
+
+            EFEN=$(expand_fen $THISFEN)
+
+            # translate move to index_from, index_to, the start position has no
+            # move so nothing is highlighted
+            IMOVE=00,00
+            [[ -n "${move}" && ${move} != "0000" ]] && IMOVE=$(move_to_i $move ${REVERSE})
+
+            # every page is a row of pages.csv, preceded by its name
+            PAGE_NAME=puzzle-${PUZZLE[0]}-${RATING}-${THEME_TAG}-$(printf '%02d' ${page})
+            ROW=${PUZZLE[0]},${EFEN},${IMOVE},${page},${#PAGE_MOVES[@]}
+            echo ${PAGE_NAME},${ROW} >> ${PUZZLES_DIR}/pages.csv
+            [ ${PAGE_FILES} = "true" ] && echo ${ROW} > ${PUZZLES_DIR}/${PAGE_NAME}.txt
+            log trace "Processed move $((i + 1)) of ${PUZZLE[0]} ♙"
+
+            page=$(($page+1))
+
+        done
+
+        # keep track of every puzzle and its themes, used to order the rom
+        echo puzzle-${PUZZLE[0]}-${RATING}-${THEME_TAG},${RATING},${PUZZLE[7]} >> ${PUZZLES_DIR}/index.csv
+
+        [ ${page_count} -ge ${MAX_NUM_PAGES} ] && \
+        {
+            [ ${VERBOSE} = "true" -o ${DRY_RUN} = "true" ] && log info "Maximum pages limit (${MAX_NUM_PAGES})"
+            break
+        }
+        [[ ${MAX_PUZZLES} -gt 0 && ${puzzle_count} -ge ${MAX_PUZZLES} ]] && \
+        {
+            [ ${VERBOSE} = "true" -o ${DRY_RUN} = "true" ] && log info "Maximum puzzles limit (${MAX_PUZZLES})"
+            break
+        }
+        [[ ${#BUCKETS[@]} -gt 0 && ${full_buckets} -eq ${#BUCKETS[@]} ]] && \
+        {
+            [ ${VERBOSE} = "true" -o ${DRY_RUN} = "true" ] && log info "All rating buckets are full"
+            break
+        }
 
     done
+    [ ${RELAXED_PASS} = "false" ] && processed_count=${read_count}
+    # the rest of a file is left unread when a limit is reached
+    [ -f /dev/stdin ] && NOT_READ=$(( NOT_READ + $(wc -l) ))
+}
 
-    # Check for last moved piece.
-    # NOTE: This filter can only be applied after all moves has been processed and requires deleting previous files.
-    # Probably there is a more efficient way to do this
-    [[ ${MOVED_PIECE,,} == *["${LAST_MOVED_PIECES,,}"]* ]] || \
-    {
-        [ ${VERBOSE} = "true" ] && echo "Skipped ${PUZZLE[0]}: last move piece ${MOVED_PIECE} not in last-move-pieces set"
-        # remove this puzzle and update counters
-        cd ./output
-        rm -fr ${OUTFILE_PREFIX}*.txt
-        puzzle_count=$(($puzzle_count-1))
-        page_count=$(( $page_count - ${#MOVES[@]} ))
-        cd ..
-    }
-    [ ${page_count} -gt ${MAX_NUM_PAGES} ] && \
-    {
-        [ ${VERBOSE} = "true" -o ${DRY_RUN} = "true" ] && echo "Maximum pages limit (${MAX_NUM_PAGES})"
-        break
-    }
+generate_puzzles
+# only a run writing to the work directory saves checkpoints
+[[ ${DRY_RUN} = "false" && ${NO_INTERMEDIATE} = "false" ]] && rm -f ${CHECKPOINT_FILE}
 
+# top up the remaining space with a second pass with wider rating bounds
+if [[ -n ${FILL_RELAXED} && ${page_count} -lt ${MAX_NUM_PAGES} ]] && \
+    [[ ${MAX_PUZZLES} -eq 0 || ${puzzle_count} -lt ${MAX_PUZZLES} ]]
+then
+    if [ -f /dev/stdin ]
+    then
+        [ ${VERBOSE} = "true" -o ${DRY_RUN} = "true" ] && \
+            log info "Filling up with puzzles ${FILL_RELAXED} rating points out of bounds"
+        MIN_RATING=$(( MIN_RATING - FILL_RELAXED ))
+        MAX_RATING=$(( MAX_RATING + FILL_RELAXED ))
+        # only puzzles skipped by the second pass are reported
+        skipped_count=0
+        SKIPPED_BY_REASON=()
+        RELAXED_PASS=true
+        RESUME_LINES=0
+        generate_puzzles < /dev/stdin
+    else
+        log warn "--fill-relaxed needs the puzzles from a file, not a pipe"
+    fi
+fi
+
+Kbytes=$(( ${page_count}*ROW_SIZE/1024 ))
+[ ${VERBOSE} = "true" -o ${DRY_RUN} = "true" ] && log info "Generated ${puzzle_count} puzzles"
+[ ${VERBOSE} = "true" -o ${DRY_RUN} = "true" ] && log info "and a total of ${page_count} screens/pages ($Kbytes KB)"
+# puzzles never looked at because a limit was reached first, unknown when
+# reading from a pipe
+[ ${NOT_READ} -gt 0 ] && SKIPPED_BY_REASON[capacity]=${NOT_READ}
+if [ ${VERBOSE} = "true" -o ${DRY_RUN} = "true" ] && [ ${#SKIPPED_BY_REASON[@]} -gt 0 ]
+then
+    # a log line a reason, for --log-format json
+    log info "Skipped puzzles by reason:"
+    while read -r reason
+    do
+        log info "$(printf "  %-20s %8d" "${reason}" ${SKIPPED_BY_REASON[${reason}]})"
+    done < <(printf '%s\n' "${!SKIPPED_BY_REASON[@]}" | sort)
+fi
+# reason,count of the skipped puzzles, for the fenpuzzles_toflash.sh --report
+for reason in "${!SKIPPED_BY_REASON[@]}"
+do
+    echo "${reason},${SKIPPED_BY_REASON[${reason}]}"
+done | sort > ${PUZZLES_DIR}/skipped.csv
+if [ ${PLAN} = "true" ]
+then
+    # the widest rating window and --max-moves whose pages fit, the next ones
+    # in steps of PLAN_RATING_STEP points or one move do not
+    PLAN_RATING_STEP=10
+    touch ${PUZZLES_DIR}/plan.csv
+    python -c $"puzzles = [[int(n) for n in line.split(',')] for line in open('${PUZZLES_DIR}/plan.csv')]
+capacity = ${PLAN_MAX_PAGES}
+
+def pages(min_rating, max_rating, max_moves):
+    selected = [p for r, m, p in puzzles if min_rating <= r <= max_rating and m <= max_moves]
+    return len(selected), sum(selected)
+
+if not puzzles:
+    raise SystemExit('No puzzles pass the other filters')
+ratings = [r for r, _, _ in puzzles]
+low, high = max(${PLAN_MIN_RATING}, min(ratings)), min(${PLAN_MAX_RATING}, max(ratings))
+count, used = pages(low, high, ${PLAN_MAX_MOVES})
+print('Capacity of %d pages, the filters select %d puzzles in %d pages (%d%%)' % (
+    capacity, count, used, used * 100 // capacity))
+widest = None
+for step in range(-((high - low) // 2), 10001, ${PLAN_RATING_STEP}):
+    if pages(low - step, high + step, ${PLAN_MAX_MOVES})[1] > capacity:
+        break
+    widest = step
+    # no wider window has more puzzles
+    if low - step <= min(ratings) and high + step >= max(ratings):
+        break
+if widest is None:
+    print('Rating window: even %d-%d takes more than the capacity' % (low + (high - low) // 2, low + (high - low) // 2))
+else:
+    count, used = pages(low - widest, high + widest, ${PLAN_MAX_MOVES})
+    print('Rating window: --max-rating %d --min-rating %d, %d puzzles in %d pages (%d%%)%s' % (
+        low - widest, high + widest, count, used, used * 100 // capacity,
+        ', all of them, relax the other filters to fill more' if count == len(puzzles) else ''))
+moves = [m for _, m, _ in puzzles]
+most = None
+for max_moves in range(min(moves), max(moves) + 1):
+    if pages(low, high, max_moves)[1] > capacity:
+        break
+    most = max_moves
+if most is None:
+    print('Max moves: even --max-moves %d takes more than the capacity' % min(moves))
+else:
+    count, used = pages(low, high, most)
+    print('Max moves: --max-moves %d, %d puzzles in %d pages (%d%%)%s' % (
+        most, count, used, used * 100 // capacity,
+        ', all of them, widen the rating window to fill more' if most == max(moves) else ''))"
+elif [ ${STATS} = "true" ]
+then
+    # one bar of at most STATS_WIDTH chars per rating range of
+    # STATS_RATING_STEP points, move count, theme and piece count
+    STATS_RATING_STEP=100
+    STATS_WIDTH=50
+    touch ${PUZZLES_DIR}/stats.csv
+    python -c $"from collections import Counter
+
+ratings, moves, pieces, themes = Counter(), Counter(), Counter(), Counter()
+for line in open('${PUZZLES_DIR}/stats.csv'):
+    rating, move_count, piece_count, puzzle_themes = line.rstrip('\\n').split(',', 3)
+    low = int(rating) // ${STATS_RATING_STEP} * ${STATS_RATING_STEP}
+    ratings['%d-%d' % (low, low + ${STATS_RATING_STEP} - 1)] += 1
+    moves[int(move_count)] += 1
+    pieces[int(piece_count)] += 1
+    themes.update(puzzle_themes.split())
+
+def histogram(title, counts, keys):
+    print(title)
+    for key in keys:
+        print('  %-20s %8d %s' % (key, counts[key], '#' * -(-counts[key] * ${STATS_WIDTH} // max(counts.values()))))
+
+if not ratings:
+    raise SystemExit('No puzzles selected')
+histogram('Rating', ratings, sorted(ratings, key=lambda bucket: int(bucket.split('-')[0])))
+histogram('Moves', moves, sorted(moves))
+histogram('Themes', themes, [theme for theme, _ in themes.most_common()])
+histogram('Pieces', pieces, sorted(pieces))"
+elif [ ${DRY_RUN} = "true" ]
+then
+    [ ${error_count} -gt 0 ] && log warn "${error_count} malformed rows"
+    # the rom made the same way as for real, the output and report last so
+    # that they always stay in the temporary directory
+    if ./fenpuzzles_toflash.sh --row-size ${ROW_SIZE} --flash-size ${FLASH_SIZE} "${TOFLASH_ARGS[@]}" \
+        --output ${DRY_RUN_DIR}/lightnote.rom \
+        --report ${DRY_RUN_DIR}/report.json ${PUZZLES_DIR} > /dev/null 2>&1
+    then
+        log info "$(python -c $"import json
+report = json.load(open('${DRY_RUN_DIR}/report.json'))
+print('Rom of %d bytes with %d puzzles in %d pages, %d bytes free' % (
+    report['flash_size'], report['puzzles'], report['pages'], report['free_bytes']))")"
+    else
+        log warn "fenpuzzles_toflash.sh failed, no rom size"
+    fi
+else
+    [ ${error_count} -gt 0 ] && log warn "${error_count} malformed rows written to ${ERRORS_FILE}"
+    [ ${NO_INTERMEDIATE} = "true" ] && \
+        { ./fenpuzzles_toflash.sh --log-format ${LOG_FORMAT} --row-size ${ROW_SIZE} --flash-size ${FLASH_SIZE} \
+            "${TOFLASH_ARGS[@]}" ${PUZZLES_DIR} || exit 1; }
+fi
+# remember every puzzle of this database for the next --newer-than run
+if [[ -n "${NEWER_THAN}" && ${DRY_RUN} = "false" ]]
+then
+    # puzzles numbered in order have no id to remember them by
+    if [ ${COLUMN_INDEX[0]} -lt 0 ]
+    then
+        log warn "--newer-than needs puzzles with a PuzzleId column, ${NEWER_THAN} not updated"
+    elif [ -f /dev/stdin ]
+    then
+        { [ -f "${NEWER_THAN}" ] && cat "${NEWER_THAN}"; tail -n +$(( HEADER_LINES + 1 )) /dev/stdin | cut -d, -f$(( COLUMN_INDEX[0] + 1 )); } | \
+            sort -u > "${NEWER_THAN}.tmp" && mv "${NEWER_THAN}.tmp" "${NEWER_THAN}"
+    else
+        log warn "--newer-than needs the puzzles from a file, not a pipe, ${NEWER_THAN} not updated"
+    fi
+fi
+for theme in "${!THEME_QUOTA[@]}"
+do
+    log info "Theme ${theme}: ${THEME_COUNT[$theme]} of ${THEME_QUOTA[$theme]} puzzles, ${THEME_OVERFLOW[$theme]} skipped over quota"
 done
-
-Kbytes=$(( ${page_count}*96/1024 ))
-[ ${VERBOSE} = "true" -o ${DRY_RUN} = "true" ] && echo "Generated ${puzzle_count} puzzles"
-[ ${VERBOSE} = "true" -o ${DRY_RUN} = "true" ] && echo "and a total of ${page_count} screens/pages ($Kbytes KB)"
-echo
+[ ${LOG_FORMAT} = "json" ] || echo
+if [ ${SUMMARY_FORMAT} = "json" ]
+then
+    # the puzzles directory, or the rom made of it, nothing for a dry run
+    OUTPUT=""
+    [ ${DRY_RUN} = "false" ] && OUTPUT=${PUZZLES_DIR}
+    if [ ${NO_INTERMEDIATE} = "true" ]
+    then
+        OUTPUT=lightnote.rom
+        for (( i=0; i<${#TOFLASH_ARGS[@]}; i++ ))
+        do
+            case ${TOFLASH_ARGS[$i]} in
+                -o | --output ) OUTPUT=${TOFLASH_ARGS[$((i + 1))]} ;;
+                --output=* ) OUTPUT=${TOFLASH_ARGS[$i]#*=} ;;
+            esac
+        done
+    fi
+    python -c $"import json
+skipped = {}
+for line in open('${PUZZLES_DIR}/skipped.csv'):
+    reason, count = line.rstrip('\\n').rsplit(',', 1)
+    skipped[reason] = int(count)
+print(json.dumps({
+    'processed': ${processed_count},
+    'selected': ${puzzle_count},
+    'skipped': skipped,
+    'malformed': ${error_count},
+    'pages': ${page_count},
+    'bytes': ${page_count} * ${ROW_SIZE},
+    'output': '${OUTPUT}' or None}))" >&3
+fi
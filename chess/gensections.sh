@@ -0,0 +1,63 @@
+#! /bin/bash
+#
+# Generate a rom made of several sections, each one with its own filters.
+# Sections are described in a file, one per line, in the order they are
+# packed:
+#
+#   <name> <percentage of the flash> <genfenpuzzles.sh options>
+#
+# e.g.
+#
+#   mates 40 --theme-tag mateIn2
+#   endgames 40 --theme-tag endgame --max-pieces 10
+#   mixed 20
+#
+# Empty lines and lines starting with # are ignored.  For a board with
+# another flash chip than the usual 16M, its size is given before the
+# sections with a line like
+#
+#   flash-size 8M
+#
+# and the same for firmware with another page size, e.g. row-size 128, or
+# other fields in its puzzle pages, e.g. row-template id,rating,efen,page,total
+# (see --row-template of fenpuzzles_toflash.sh).
+#
+# Any argument after the puzzles file is passed on to fenpuzzles_toflash.sh
+
+function print_usage() {
+    echo "$0 SECTIONS_FILE PUZZLES_CSV [fenpuzzles_toflash.sh options]"
+}
+
+[ $# -lt 2 ] && { print_usage; exit 1; }
+SECTIONS_FILE=$1
+PUZZLES=$2
+shift 2
+
+FLASH_SIZE=16777216
+ROW_SIZE=96
+ROW_TEMPLATE=""
+CONFIG_SECTOR_SIZE=0x1000
+SECTIONS_DIR=sections
+
+. ./functions.sh
+
+rm -fr ${SECTIONS_DIR}
+mkdir ${SECTIONS_DIR}
+[ -d output ] || mkdir output
+
+section_count=0
+SECTION_DIRS=()
+while read -r name percent options
+do
+    [[ -z ${name} || ${name} == \#* ]] && continue
+    [ ${name} = "flash-size" ] && { FLASH_SIZE=$(to_bytes ${percent}); continue; }
+    [ ${name} = "row-size" ] && { ROW_SIZE=${percent}; continue; }
+    [ ${name} = "row-template" ] && { ROW_TEMPLATE=${percent}; continue; }
+    section_count=$((section_count + 1))
+    echo "Generating section ${name} (${percent}% of the flash)..."
+    dir=${SECTIONS_DIR}/$(printf '%02d' ${section_count})-${name}
+    ./genfenpuzzles.sh --row-size ${ROW_SIZE} --flash-size ${FLASH_SIZE} --max-bytes $(( (FLASH_SIZE - CONFIG_SECTOR_SIZE) * percent / 100 )) ${options} --work-dir ${dir} < ${PUZZLES} || exit 1
+    SECTION_DIRS+=(${dir})
+done < ${SECTIONS_FILE}
+
+./fenpuzzles_toflash.sh --flash-size ${FLASH_SIZE} --row-size ${ROW_SIZE} ${ROW_TEMPLATE:+--row-template ${ROW_TEMPLATE}} "$@" ${SECTION_DIRS[@]}
@@ -0,0 +1,102 @@
+#! /usr/bin/env python
+"""Get puzzles from the lichess API instead of the whole puzzle database.
+
+Fine for small themed builds, puzzles are asked one at a time and lichess
+asks API users not to do several requests at once.  Every puzzle received is
+kept in the cache directory and used again by later runs, so the API is only
+asked for what is missing.
+
+usage: lichess_api.py [--theme THEME] [--min-rating R] [--max-rating R]
+                      [--count N] [--cache-dir DIR] > puzzles.csv
+"""
+import argparse
+import glob
+import json
+import os
+import sys
+import time
+import urllib.error
+import urllib.request
+
+from pgn2csv import Position, START_FEN
+
+API_URL = 'https://lichess.org/api/puzzle/next'
+# seconds between requests, and after being told to slow down
+REQUEST_DELAY = 1
+RATE_LIMIT_DELAY = 60
+# give up when the API keeps sending puzzles that do not match
+MAX_REQUESTS_PER_PUZZLE = 10
+
+
+def request(theme):
+    url = API_URL + ('?angle=' + theme if theme else '')
+    while True:
+        try:
+            with urllib.request.urlopen(url) as response:
+                return json.load(response)
+        except urllib.error.HTTPError as error:
+            if error.code != 429:
+                raise
+            print('Rate limited, waiting %d seconds' % RATE_LIMIT_DELAY, file=sys.stderr)
+            time.sleep(RATE_LIMIT_DELAY)
+
+
+def csv_row(data):
+    """the puzzle as a row of the lichess puzzles csv.  The game pgn ends with
+    the opponent move that starts the puzzle, the fen is before that move"""
+    puzzle, game = data['puzzle'], data['game']
+    moves = game['pgn'].split()
+    position = Position(START_FEN)
+    for san in moves[:-1]:
+        position.uci(san)
+    fen = position.fen(len(moves) // 2 + 1)
+    setup_move = position.uci(moves[-1])
+    return ','.join([puzzle['id'], fen, ' '.join([setup_move] + puzzle['solution']),
+                     str(puzzle['rating']), '0', '0', str(puzzle.get('plays', 0)),
+                     ' '.join(puzzle['themes']), 'https://lichess.org/' + game['id'], ''])
+
+
+def matches(data, args):
+    puzzle = data['puzzle']
+    return (not args.theme or args.theme in puzzle['themes']) and \
+        args.min_rating <= puzzle['rating'] <= args.max_rating
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.split('\n')[0])
+    parser.add_argument('--theme', help='lichess puzzle theme, e.g. fork')
+    parser.add_argument('--min-rating', type=int, default=0)
+    parser.add_argument('--max-rating', type=int, default=10000)
+    parser.add_argument('--count', type=int, default=100, help='number of puzzles')
+    parser.add_argument('--cache-dir', default=os.path.expanduser('~/.cache/lightnote/api'))
+    args = parser.parse_args()
+
+    os.makedirs(args.cache_dir, exist_ok=True)
+    puzzles = {}
+    for path in sorted(glob.glob(os.path.join(args.cache_dir, '*.json'))):
+        data = json.load(open(path))
+        if matches(data, args):
+            puzzles[data['puzzle']['id']] = data
+
+    requests = 0
+    while len(puzzles) < args.count and requests < args.count * MAX_REQUESTS_PER_PUZZLE:
+        data = request(args.theme)
+        requests += 1
+        with open(os.path.join(args.cache_dir, data['puzzle']['id'] + '.json'), 'w') as f:
+            json.dump(data, f)
+        if matches(data, args):
+            puzzles[data['puzzle']['id']] = data
+            print('\r%d of %d puzzles' % (len(puzzles), args.count), end='', file=sys.stderr)
+        time.sleep(REQUEST_DELAY)
+    print(file=sys.stderr)
+
+    print('PuzzleId,FEN,Moves,Rating,RatingDeviation,Popularity,NbPlays,Themes,GameUrl,OpeningTags')
+    for data in list(puzzles.values())[:args.count]:
+        try:
+            print(csv_row(data))
+        except (ValueError, KeyError) as error:
+            print('%s: skipped, %s' % (data['puzzle']['id'], error), file=sys.stderr)
+
+
+if __name__ == '__main__':
+    main()
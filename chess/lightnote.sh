@@ -0,0 +1,63 @@
+#! /bin/bash
+#
+# One entry point for the scripts, by subcommand:
+#
+#   ./lightnote.sh [--row-size N] [--flash-size SIZE] COMMAND [OPTIONS]
+#
+# The global options go to every command that takes them, before the options
+# of the command so that those win, gensections.sh reads them from its
+# sections file instead.  See ./lightnote.sh COMMAND -h for the options of a command.
+
+function print_usage() {
+    echo "$0 [--row-size N] [--flash-size SIZE] COMMAND [OPTIONS]"
+    echo "--row-size: bytes of every page in the rom, for generate, stats and build"
+    echo "--flash-size: size of the flash chip of the board, for generate, stats, build and merge"
+    echo
+    echo "commands:"
+    echo "  fetch      download the lichess puzzle database (fetch.sh)"
+    echo "  generate   select puzzles and write their pages to fenpuzzles (genfenpuzzles.sh)"
+    echo "  stats      histograms of the puzzles generate would select (genfenpuzzles.sh --stats)"
+    echo "  build      make the rom of the puzzles (fenpuzzles_toflash.sh)"
+    echo "  sections   generate and build a rom of several sections (gensections.sh)"
+    echo "  inspect    print the config sector and the first pages of a rom"
+    echo "  preview    print the boards of the first pages of a rom (romtool.py inspect --rows ${PREVIEW_ROWS})"
+    echo "  verify     check a rom before flashing it"
+    echo "  unpack     write the pages of a rom back to a puzzles directory"
+    echo "  diff       puzzles added, removed or changed between two roms"
+    echo "  merge      one rom with the puzzles of several roms"
+    echo "  convert    write a rom as uf2 or srec"
+    echo "  flash      write a rom to the board, as uf2, with picotool or flashrom (flash.sh)"
+    echo "  manifest   write a JSON manifest of the puzzles of a rom"
+}
+
+# pages printed by preview, --rows after it prints more or less
+PREVIEW_ROWS=20
+ROW_SIZE=""
+FLASH_SIZE=""
+while [ $# -gt 0 ]; do
+  case "$1" in
+    --row-size ) ROW_SIZE="$2"; shift 2 ;;
+    --flash-size ) FLASH_SIZE="$2"; shift 2 ;;
+    -h | --help ) print_usage; exit 0 ;;
+    -* ) echo "Unknown option $1" >&2; print_usage >&2; exit 1 ;;
+    * ) break ;;
+  esac
+done
+[ $# -eq 0 ] && { print_usage >&2; exit 1; }
+COMMAND=$1
+shift
+
+ROW_SIZE_OPTION=(${ROW_SIZE:+--row-size ${ROW_SIZE}})
+FLASH_SIZE_OPTION=(${FLASH_SIZE:+--flash-size ${FLASH_SIZE}})
+case ${COMMAND} in
+    fetch ) exec ./fetch.sh "$@" ;;
+    generate ) exec ./genfenpuzzles.sh "${ROW_SIZE_OPTION[@]}" "${FLASH_SIZE_OPTION[@]}" "$@" ;;
+    stats ) exec ./genfenpuzzles.sh "${ROW_SIZE_OPTION[@]}" "${FLASH_SIZE_OPTION[@]}" --stats "$@" ;;
+    build ) exec ./fenpuzzles_toflash.sh "${ROW_SIZE_OPTION[@]}" "${FLASH_SIZE_OPTION[@]}" "$@" ;;
+    sections ) exec ./gensections.sh "$@" ;;
+    merge ) exec ./romtool.py merge "${FLASH_SIZE_OPTION[@]}" "$@" ;;
+    flash ) exec ./flash.sh "$@" ;;
+    preview ) exec ./romtool.py inspect --rows ${PREVIEW_ROWS} "$@" ;;
+    inspect | verify | unpack | diff | convert | manifest ) exec ./romtool.py ${COMMAND} "$@" ;;
+    * ) echo "Unknown command ${COMMAND}" >&2; print_usage >&2; exit 1 ;;
+esac
@@ -0,0 +1,226 @@
+#! /usr/bin/env python
+"""Turn PGN files, e.g. exported lichess studies, into a puzzles csv.
+
+Every game (study chapter) is a puzzle starting from its FEN tag, with its
+mainline as solution.  The solver plays the side of the Orientation tag, or
+the side to move when there is none.  Puzzles in the lichess csv start with
+an opponent move, a null move (0000) is added when the solver moves first.
+
+usage: pgn2csv.py PGN_FILE_OR_DIR... > puzzles.csv
+"""
+import os
+import re
+import sys
+
+START_FEN = 'rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1'
+RATING = 1500
+THEME = 'study'
+
+KNIGHT = [(1, 2), (2, 1), (2, -1), (1, -2), (-1, -2), (-2, -1), (-2, 1), (-1, 2)]
+KING = [(1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1), (0, -1), (1, -1)]
+SAN = re.compile(r'^([NBRQK])?([a-h])?([1-8])?x?([a-h][1-8])(?:=?([NBRQ]))?$')
+
+
+def square(name):
+    return ord(name[0]) - ord('a'), int(name[1]) - 1
+
+
+def name(sq):
+    return chr(ord('a') + sq[0]) + str(sq[1] + 1)
+
+
+class Position:
+    def __init__(self, fen):
+        fields = fen.split()
+        self.board = {}
+        for rank, row in zip(range(7, -1, -1), fields[0].split('/')):
+            file = 0
+            for c in row:
+                if c.isdigit():
+                    file += int(c)
+                else:
+                    self.board[(file, rank)] = c
+                    file += 1
+        self.white = fields[1] == 'w'
+        self.ep = square(fields[3]) if len(fields) > 3 and fields[3] != '-' else None
+
+    def fen(self, move_number=1):
+        """the position as a fen, without castling rights"""
+        rows = []
+        for rank in range(7, -1, -1):
+            row, empty = '', 0
+            for file in range(8):
+                piece = self.board.get((file, rank))
+                if piece:
+                    row += (str(empty) if empty else '') + piece
+                    empty = 0
+                else:
+                    empty += 1
+            rows.append(row + (str(empty) if empty else ''))
+        ep = name(self.ep) if self.ep else '-'
+        return '%s %s - %s 0 %d' % ('/'.join(rows), 'w' if self.white else 'b', ep, move_number)
+
+    def own(self, piece):
+        return piece is not None and piece.isupper() == self.white
+
+    def reaches(self, frm, to, piece):
+        """the piece on frm attacks to, pawns excluded"""
+        dx, dy = to[0] - frm[0], to[1] - frm[1]
+        kind = piece.upper()
+        if kind == 'N':
+            return (dx, dy) in KNIGHT
+        if kind == 'K':
+            return (dx, dy) in KING
+        if kind == 'R' and dx and dy:
+            return False
+        if kind == 'B' and abs(dx) != abs(dy):
+            return False
+        if kind == 'Q' and dx and dy and abs(dx) != abs(dy):
+            return False
+        if not dx and not dy:
+            return False
+        step = ((dx > 0) - (dx < 0), (dy > 0) - (dy < 0))
+        sq = (frm[0] + step[0], frm[1] + step[1])
+        while sq != to:
+            if sq in self.board:
+                return False
+            sq = (sq[0] + step[0], sq[1] + step[1])
+        return True
+
+    def attacked(self, sq, by_white):
+        for frm, piece in self.board.items():
+            if piece.isupper() != by_white:
+                continue
+            if piece.upper() == 'P':
+                forward = 1 if by_white else -1
+                if sq[1] - frm[1] == forward and abs(sq[0] - frm[0]) == 1:
+                    return True
+            elif self.reaches(frm, sq, piece):
+                return True
+        return False
+
+    def play(self, frm, to, promotion=None):
+        """apply a move, including castling, en passant and promotions"""
+        piece = self.board.pop(frm)
+        if piece.upper() == 'P' and to == self.ep:
+            self.board.pop((to[0], frm[1]), None)
+        if piece.upper() == 'K' and abs(to[0] - frm[0]) == 2:
+            rook_from = (7 if to[0] > frm[0] else 0, frm[1])
+            self.board[((frm[0] + to[0]) // 2, frm[1])] = self.board.pop(rook_from)
+        if promotion:
+            piece = promotion.upper() if self.white else promotion.lower()
+        self.board[to] = piece
+        self.ep = None
+        if piece.upper() == 'P' and abs(to[1] - frm[1]) == 2:
+            self.ep = (frm[0], (frm[1] + to[1]) // 2)
+        self.white = not self.white
+
+    def legal(self, frm, to):
+        """the move does not leave the own king in check"""
+        board, ep, white = dict(self.board), self.ep, self.white
+        self.play(frm, to)
+        king = next(sq for sq, p in self.board.items() if p == ('K' if white else 'k'))
+        ok = not self.attacked(king, not white)
+        self.board, self.ep, self.white = board, ep, white
+        return ok
+
+    def uci(self, san):
+        """translate a move in SAN to UCI and play it"""
+        san = san.rstrip('+#!?')
+        rank = 0 if self.white else 7
+        if san in ('O-O', '0-0', 'O-O-O', '0-0-0'):
+            frm, to = (4, rank), (6 if len(san) == 3 else 2, rank)
+            self.play(frm, to)
+            return name(frm) + name(to)
+        match = SAN.match(san)
+        if not match:
+            raise ValueError('invalid move ' + san)
+        kind, file, rnk, to, promotion = match.groups()
+        to = square(to)
+        candidates = []
+        for frm, piece in list(self.board.items()):
+            if not self.own(piece) or piece.upper() != (kind or 'P'):
+                continue
+            if file and frm[0] != ord(file) - ord('a'):
+                continue
+            if rnk and frm[1] != int(rnk) - 1:
+                continue
+            if kind:
+                reaches = self.reaches(frm, to, piece)
+            else:
+                forward = 1 if self.white else -1
+                start = 1 if self.white else 6
+                if to[0] == frm[0]:
+                    reaches = to not in self.board and (to[1] - frm[1] == forward or (
+                        frm[1] == start and to[1] - frm[1] == 2 * forward and
+                        (frm[0], frm[1] + forward) not in self.board))
+                else:
+                    target = self.board.get(to)
+                    reaches = abs(to[0] - frm[0]) == 1 and to[1] - frm[1] == forward and (
+                        (target is not None and not self.own(target)) or to == self.ep)
+            if reaches and self.legal(frm, to):
+                candidates.append(frm)
+        if len(candidates) != 1:
+            raise ValueError('cannot play ' + san)
+        self.play(candidates[0], to, promotion)
+        return name(candidates[0]) + name(to) + (promotion or '').lower()
+
+
+def mainline(movetext):
+    """SAN moves of the mainline, without comments, variations or NAGs"""
+    movetext = re.sub(r'\{[^}]*\}|;[^\n]*|\$\d+', ' ', movetext)
+    while re.search(r'\([^()]*\)', movetext):
+        movetext = re.sub(r'\([^()]*\)', ' ', movetext)
+    moves = []
+    for token in movetext.split():
+        token = re.sub(r'^\d+\.+', '', token)
+        if token and token not in ('1-0', '0-1', '1/2-1/2', '*'):
+            moves.append(token)
+    return moves
+
+
+def games(text):
+    """tags and movetext of every game in a PGN"""
+    for game in re.split(r'\n\s*\n(?=\s*\[)', text.strip()):
+        tags = dict(re.findall(r'^\s*\[(\w+)\s+"([^"]*)"\]', game, re.M))
+        movetext = re.sub(r'^\s*\[.*\]\s*$', '', game, flags=re.M)
+        yield tags, movetext
+
+
+def puzzles(path):
+    stem = re.sub(r'[^A-Za-z0-9]', '', os.path.splitext(os.path.basename(path))[0])
+    for number, (tags, movetext) in enumerate(games(open(path).read()), 1):
+        puzzle_id = '%s%02d' % (stem, number)
+        fen = tags.get('FEN', START_FEN)
+        try:
+            position = Position(fen)
+            solver_white = tags.get('Orientation', 'white' if position.white else 'black') == 'white'
+            moves = [position.uci(san) for san in mainline(movetext)]
+        except (ValueError, KeyError, StopIteration) as error:
+            print('%s: skipped, %s' % (puzzle_id, error), file=sys.stderr)
+            continue
+        if solver_white == (fen.split()[1] == 'w'):
+            # the solver moves first, the opponent passes
+            fields = fen.split()
+            fields[1] = 'b' if fields[1] == 'w' else 'w'
+            fields[3:4] = ['-'] if len(fields) > 3 else []
+            fen = ' '.join(fields)
+            moves.insert(0, '0000')
+        yield puzzle_id, fen, ' '.join(moves)
+
+
+def main():
+    paths = []
+    for arg in sys.argv[1:]:
+        if os.path.isdir(arg):
+            paths += sorted(os.path.join(arg, f) for f in os.listdir(arg) if f.endswith('.pgn'))
+        else:
+            paths.append(arg)
+    print('PuzzleId,FEN,Moves,Rating,Themes')
+    for path in paths:
+        for puzzle_id, fen, moves in puzzles(path):
+            print(','.join([puzzle_id, fen, moves, str(RATING), THEME]))
+
+
+if __name__ == '__main__':
+    main()
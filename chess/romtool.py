@@ -0,0 +1,1157 @@
+#! /usr/bin/env python
+"""Look into rom files made by fenpuzzles_toflash.sh.
+
+usage: romtool.py inspect ROM
+       romtool.py unpack ROM [DIR]
+       romtool.py verify ROM
+       romtool.py diff OLD_ROM NEW_ROM
+       romtool.py manifest [-o MANIFEST] ROM
+       romtool.py merge [-o OUT_ROM] ROM...
+       romtool.py convert ROM OUT_FILE [--format uf2|srec]
+"""
+import argparse
+import hashlib
+import json
+import math
+import os
+import struct
+import subprocess
+import sys
+import time
+import zlib
+
+CONFIG_SECTOR_SIZE = 0x1000
+MAGIC = 0x11131719
+# magic, num_pages, total_size, num_types, font_size, version, flags, type0-3, size0-3
+CONFIG_STRUCT = struct.Struct('<LLLBBBB4B4L')
+SECTION = struct.Struct('<LL')
+MAX_SECTIONS = 16
+# CRC32 of the pages, after the space for MAX_SECTIONS sections, then the
+# number of pages of every type, the index and the progress region
+CRC_OFFSET = CONFIG_STRUCT.size + 4 + MAX_SECTIONS * SECTION.size
+# how the rom was made: build time, version of these tools, git commit they
+# are from and SHA-256 of the filters.txt of the puzzle directories
+METADATA_OFFSET = 0x100
+METADATA = struct.Struct('<L16s20s32s')
+TOOL_VERSION = '1.0'
+# struct prefix of the byte orders of the config sector fields, little endian
+# unless the firmware is for a big endian MCU.  The magic tells which one a
+# rom has
+BYTE_ORDERS = {'little': '<', 'big': '>'}
+# fields of the text puzzle pages with FLAG_ROW_TEMPLATE, in their order.
+# efen is the board in 64 chars, fen the board as in a FEN (rows of digits
+# and pieces separated by slashes, 71 chars at most), move the from and to
+# indices of the move, page and total count the pages of the puzzle
+ROW_TEMPLATE_OFFSET = 0x180
+ROW_TEMPLATE_SIZE = 64
+DEFAULT_ROW_TEMPLATE = 'id,efen,move,page,total'
+# fields a template must have for the puzzles to be found, and the smallest
+# size of every field
+REQUIRED_ROW_FIELDS = ('id', 'page', 'total')
+BOARD_FIELDS = ('efen', 'fen')
+ROW_FIELD_SIZES = {'id': 5, 'efen': 64, 'fen': 17, 'move': 5, 'page': 1, 'total': 1, 'rating': 3}
+TEXT = 1
+CHESS_PUZZLE = 4
+# newest config format known, and the features of the flags byte with the
+# format version a firmware needs to read them.  Version 0 firmware ignores
+# the flags, they must be 0 for it
+FORMAT_VERSION = 3
+FLAG_PAGE_CHECKSUM = 0x01
+FLAG_INDEX = 0x02
+FLAG_PACKED_BOARD = 0x04
+FLAG_RLE = 0x08
+FLAG_SECTOR_ALIGN = 0x10
+FLAG_ROW_TEMPLATE = 0x20
+FLAG_ROW_META = 0x40
+FLAG_PUZZLE_ID = 0x80
+FLAGS = {FLAG_PAGE_CHECKSUM: ('pages end with a checksum', 1),
+         FLAG_INDEX: ('index of the puzzles after the pages', 1),
+         FLAG_PACKED_BOARD: ('puzzle pages in binary, 4 bits a square', 2),
+         FLAG_RLE: ('pages run length encoded', 2),
+         FLAG_SECTOR_ALIGN: ('puzzles aligned to erase sectors, pages of zeros are padding', 2),
+         FLAG_ROW_TEMPLATE: ('puzzle pages laid out as the row template', 3),
+         FLAG_ROW_META: ('puzzle pages end with a difficulty and theme bits', 3),
+         FLAG_PUZZLE_ID: ('puzzle pages start with the puzzle id', 3)}
+# puzzles do not span erase sectors with FLAG_SECTOR_ALIGN
+ERASE_SECTOR_SIZE = 0x1000
+# id, first_page, num_pages and rating of a puzzle in the index.  The pages
+# of a puzzle are always its own, one after the other, even with a row
+# template without id or page where two puzzles may have the same page: the
+# firmware reads a puzzle as a run of pages, from the index or walking them,
+# so a page in the middle of a puzzle cannot be another puzzle's as well
+INDEX_ENTRY = struct.Struct('<16sLHH')
+# a puzzle page with FLAG_PACKED_BOARD: the board, 2 squares a byte from a8
+# with the first square in the high nibble, from, to, page, total and the
+# length of the id that follows
+PACKED_ROW = struct.Struct('<32s4BB')
+# id of the puzzle with FLAG_PUZZLE_ID, at offset 0 of every page of the
+# puzzle, ascii padded with zeros and not run length encoded, for the
+# firmware to show lichess.org/training/<id> without parsing the page
+PUZZLE_ID_SIZE = 8
+# difficulty and theme bits of a puzzle with FLAG_ROW_META, at the end of
+# every page of the puzzle, before the checksum byte and not run length
+# encoded.  The difficulty is the rating / 16, 0 when unknown, bit i of the
+# themes is THEME_BITS[i]
+ROW_META = struct.Struct('<BL')
+THEME_BITS = ['opening', 'middlegame', 'endgame', 'mate', 'mateIn1', 'mateIn2', 'mateIn3', 'mateIn4',
+              'mateIn5', 'fork', 'pin', 'skewer', 'discoveredAttack', 'doubleCheck', 'sacrifice', 'deflection',
+              'attraction', 'clearance', 'interference', 'intermezzo', 'quietMove', 'defensiveMove',
+              'hangingPiece', 'trappedPiece', 'capturingDefender', 'promotion', 'advancedPawn', 'backRankMate',
+              'smotheredMate', 'kingsideAttack', 'zugzwang', 'xRayAttack']
+# piece of every nibble value, 1 is an empty square and ? unused
+PACKED_PIECES = '1PNBRQK??pnbrqk'
+PIECES = set('prnbqkPRNBQK1')
+# errors printed by verify, the rest are only counted
+MAX_ERRORS = 20
+# UF2 blocks of 256 bytes of the rom, for the RP2040 bootloader drive
+UF2_BLOCK = struct.Struct('<8L476sL')
+UF2_MAGIC = (0x0a324655, 0x9e5d5157, 0x0ab16f30)
+UF2_FLAG_FAMILY_ID = 0x2000
+UF2_RP2040 = 0xe48bff56
+UF2_PAYLOAD_SIZE = 256
+# where the flash of the RP2040 is mapped, from the start of the flash
+FLASH_ADDRESS = 0x10000000
+# bytes of rom in every S-record
+SREC_DATA_SIZE = 32
+TYPES = {0: 'Unused', 1: 'Text', 2: 'RawImage', 3: 'Sensors', 4: 'ChessPuzzle'}
+
+
+class ConfigSector:
+    """the last sector of the flash, read by the firmware to find the pages:
+
+    magic: u32, num_pages: u32, total_size: u32, num_types: u8, font_size: u8,
+    version: u8, flags: u8, type0-type3: u8, size0-size3: u32,
+    num_sections: u8 and 3 reserved bytes, first_page: u32 and num_pages: u32
+    of up to MAX_SECTIONS sections, data_crc32: u32 at CRC_OFFSET,
+    type_pages0-type_pages3: u32, index_offset: u32, index_count: u32,
+    progress_offset: u32, progress_sectors: u32, build_time: u32 at
+    METADATA_OFFSET, tool_version: 16 chars, git_commit: 20 bytes,
+    filters_sha256: 32 bytes, row_template: 64 chars at ROW_TEMPLATE_OFFSET,
+    zeros up to CONFIG_SECTOR_SIZE.  The fields are little endian, or all
+    big endian for a big endian firmware.
+
+    The progress region is left erased for the firmware to keep which puzzles
+    are solved, it is not part of the pages"""
+
+    def __init__(self, num_pages=0, total_size=0, num_types=0, font_size=1, version=0, flags=0,
+                 types=(0, 0, 0, 0), sizes=(0, 0, 0, 0), sections=(), data_crc32=0,
+                 type_pages=(0, 0, 0, 0), index_offset=0, index_count=0, progress_offset=0,
+                 progress_sectors=0, build_time=0, tool_version='', git_commit=bytes(20),
+                 filters_sha256=bytes(32), row_template='', magic=MAGIC, byte_order='little'):
+        self.magic = magic
+        self.byte_order = byte_order
+        self.num_pages = num_pages
+        self.total_size = total_size
+        self.num_types = num_types
+        self.font_size = font_size
+        self.version = version
+        self.flags = flags
+        self.types = list(types)
+        self.sizes = list(sizes)
+        self.sections = [tuple(section) for section in sections]
+        self.data_crc32 = data_crc32
+        self.type_pages = list(type_pages)
+        self.index_offset = index_offset
+        self.index_count = index_count
+        self.progress_offset = progress_offset
+        self.progress_sectors = progress_sectors
+        self.build_time = build_time
+        self.tool_version = tool_version
+        self.git_commit = git_commit
+        self.filters_sha256 = filters_sha256
+        self.row_template = row_template
+
+    @classmethod
+    def build(cls, types, sections, data_crc32, font_size=1, version=0, flags=0,
+              index_offset=0, index_count=0, progress_offset=0, progress_sectors=0,
+              filters_sha256=bytes(32), row_template='', byte_order='little'):
+        """the config of a rom with types as (type, row size, pages), in the
+        order they are stored, and sections as (first_page, num_pages), built
+        now by these tools.  SOURCE_DATE_EPOCH, if set, is the build time so
+        the same pages always make the same rom"""
+        types = list(types) + [(0, 0, 0)] * (4 - len(types))
+        return cls(num_pages=sum(t[2] for t in types),
+                   total_size=sum(size * pages for _, size, pages in types),
+                   num_types=len([t for t in types if t[0]]), font_size=font_size, version=version, flags=flags,
+                   types=[t[0] for t in types], sizes=[t[1] for t in types], sections=sections,
+                   data_crc32=data_crc32, type_pages=[t[2] for t in types],
+                   index_offset=index_offset, index_count=index_count,
+                   progress_offset=progress_offset, progress_sectors=progress_sectors,
+                   build_time=int(os.environ.get('SOURCE_DATE_EPOCH') or time.time()), tool_version=TOOL_VERSION, git_commit=git_commit(),
+                   filters_sha256=filters_sha256, row_template=row_template, byte_order=byte_order)
+
+    def encode(self):
+        if len(self.sections) > MAX_SECTIONS:
+            raise ValueError('%d sections, at most %d fit' % (len(self.sections), MAX_SECTIONS))
+        order = BYTE_ORDERS[self.byte_order]
+        config = struct.pack(order + CONFIG_STRUCT.format[1:], self.magic, self.num_pages, self.total_size,
+                             self.num_types, self.font_size, self.version, self.flags, *self.types, *self.sizes)
+        config += struct.pack('<B3x', len(self.sections))
+        config += b''.join(struct.pack(order + SECTION.format[1:], *section) for section in self.sections)
+        config = config.ljust(CRC_OFFSET, b'\0')
+        config += struct.pack(order + 'L4LLLLL', self.data_crc32, *self.type_pages, self.index_offset,
+                              self.index_count, self.progress_offset, self.progress_sectors)
+        config = config.ljust(METADATA_OFFSET, b'\0')
+        config += struct.pack(order + METADATA.format[1:], self.build_time, self.tool_version.encode(),
+                              self.git_commit, self.filters_sha256)
+        config = config.ljust(ROW_TEMPLATE_OFFSET, b'\0') + self.row_template.encode().ljust(ROW_TEMPLATE_SIZE, b'\0')
+        return config.ljust(CONFIG_SECTOR_SIZE, b'\0')
+
+    @classmethod
+    def decode(cls, config):
+        byte_order = 'big' if config[:4] == MAGIC.to_bytes(4, 'big') else 'little'
+        order = BYTE_ORDERS[byte_order]
+        (magic, num_pages, total_size, num_types, font_size, version, flags,
+         *fields) = struct.unpack_from(order + CONFIG_STRUCT.format[1:], config)
+        num_sections = config[CONFIG_STRUCT.size]
+        sections = [struct.unpack_from(order + SECTION.format[1:], config, CONFIG_STRUCT.size + 4 + i * SECTION.size)
+                    for i in range(min(num_sections, MAX_SECTIONS))]
+        (data_crc32, *type_pages, index_offset, index_count, progress_offset,
+         progress_sectors) = struct.unpack_from(order + 'L4LLLLL', config, CRC_OFFSET)
+        build_time, tool_version, commit, filters_sha256 = struct.unpack_from(
+            order + METADATA.format[1:], config, METADATA_OFFSET)
+        row_template = config[ROW_TEMPLATE_OFFSET:ROW_TEMPLATE_OFFSET + ROW_TEMPLATE_SIZE]
+        return cls(num_pages, total_size, num_types, font_size, version, flags, fields[:4], fields[4:],
+                   sections, data_crc32, type_pages, index_offset, index_count, progress_offset,
+                   progress_sectors, build_time, tool_version.rstrip(b'\0').decode('ascii', 'replace'),
+                   commit, filters_sha256, row_template.rstrip(b'\0').decode('ascii', 'replace'), magic,
+                   byte_order)
+
+
+def git_commit():
+    """commit these tools are from, zeros out of a git checkout"""
+    try:
+        commit = subprocess.run(['git', 'rev-parse', 'HEAD'], cwd=os.path.dirname(os.path.abspath(__file__)),
+                                capture_output=True, text=True, check=True).stdout.strip()
+        return bytes.fromhex(commit)[:20]
+    except (OSError, subprocess.CalledProcessError, ValueError):
+        return bytes(20)
+
+
+def filters_sha256(directories):
+    """SHA-256 of the options genfenpuzzles.sh made the puzzles of the
+    directories with, zeros when none of them says"""
+    sha256 = hashlib.sha256()
+    found = False
+    for directory in directories:
+        path = os.path.join(directory, 'filters.txt')
+        if os.path.exists(path):
+            sha256.update(open(path, 'rb').read())
+            found = True
+    return sha256.digest() if found else bytes(32)
+
+
+def pack_row(row):
+    """a puzzle row in binary, see PACKED_ROW"""
+    puzzle_id, efen, move_from, move_to, page, total = row.split(',')
+    nibbles = [PACKED_PIECES.index(piece) for piece in efen]
+    board = bytes(high << 4 | low for high, low in zip(nibbles[0::2], nibbles[1::2]))
+    return PACKED_ROW.pack(board, int(move_from), int(move_to), int(page), int(total),
+                           len(puzzle_id)) + puzzle_id.encode()
+
+
+def unpack_row(data):
+    """the text of a binary puzzle row"""
+    board, move_from, move_to, page, total, id_length = PACKED_ROW.unpack_from(data)
+    efen = ''.join(PACKED_PIECES[byte >> 4] + PACKED_PIECES[byte & 0xf] for byte in board)
+    puzzle_id = data[PACKED_ROW.size:PACKED_ROW.size + id_length].decode('ascii', 'replace')
+    return '%s,%s,%02d,%02d,%d,%d' % (puzzle_id, efen, move_from, move_to, page, total)
+
+
+def row_meta(rating, themes):
+    """the difficulty and theme bits of a puzzle of this rating and themes,
+    as stored with FLAG_ROW_META"""
+    bits = sum(1 << THEME_BITS.index(theme) for theme in set(themes) if theme in THEME_BITS)
+    return ROW_META.pack(min(rating // 16, 255), bits)
+
+
+def theme_names(bits):
+    """the themes of theme bits"""
+    return [theme for i, theme in enumerate(THEME_BITS) if bits & 1 << i]
+
+
+def row_template_errors(template, row_size):
+    """what is wrong with a row template for rows of row_size bytes"""
+    fields = template.split(',')
+    errors = ['unknown field %s' % field for field in fields if field not in ROW_FIELD_SIZES]
+    errors += ['%s more than once' % field for field in sorted(set(fields)) if fields.count(field) > 1]
+    errors += ['no %s' % field for field in REQUIRED_ROW_FIELDS if field not in fields]
+    if len(set(BOARD_FIELDS) & set(fields)) != 1:
+        errors.append('not one of %s' % ' or '.join(BOARD_FIELDS))
+    if len(template) > ROW_TEMPLATE_SIZE:
+        errors.append('longer than %d characters' % ROW_TEMPLATE_SIZE)
+    # the fields, the commas between them and the newline
+    size = sum(ROW_FIELD_SIZES.get(field, 0) for field in fields) + len(fields)
+    if size > row_size:
+        errors.append('rows of at least %d bytes, more than %d' % (size, row_size))
+    return errors
+
+
+def compress_board(efen):
+    """the board of a FEN from the 64 chars of a page"""
+    ranks = [efen[i:i + 8] for i in range(0, 64, 8)]
+    return '/'.join(''.join(str(len(run)) if run[0] == '1' else run for run in runs(rank)) for rank in ranks)
+
+
+def runs(text):
+    """text in runs of empty squares and single pieces"""
+    result = []
+    for c in text:
+        if c == '1' and result and result[-1][0] == '1':
+            result[-1] += c
+        else:
+            result.append(c)
+    return result
+
+
+def expand_board(fen):
+    """the 64 chars of a page from the board of a FEN"""
+    return ''.join('1' * int(c) if c.isdigit() else c for c in fen.replace('/', ''))
+
+
+def format_row(row, template, rating):
+    """a puzzle row with the fields of the template"""
+    puzzle_id, efen, move_from, move_to, page, total = row.split(',')
+    values = {'id': puzzle_id, 'efen': efen, 'fen': compress_board(efen), 'move': move_from + ',' + move_to,
+              'page': page, 'total': total, 'rating': str(rating)}
+    return ','.join(values[field] for field in template.split(','))
+
+
+def parse_row(text, template):
+    """the puzzle row and the rating, None if not in the template, of a row
+    laid out as the template"""
+    columns = text.split(',')
+    values = {}
+    for field in template.split(','):
+        width = 2 if field == 'move' else 1
+        values[field], columns = ','.join(columns[:width]), columns[width:]
+    efen = values['efen'] if 'efen' in values else expand_board(values.get('fen', ''))
+    row = ','.join([values['id'], efen, values.get('move', '00,00'), values['page'], values['total']])
+    rating = values.get('rating', '')
+    return row, int(rating) if rating.isdigit() else None
+
+
+def rle_encode(data):
+    """run length encoding of a page, decoded one page at a time by the
+    firmware.  A byte c up to 127 is followed by c + 1 bytes as they are, a
+    byte c from 128 by one byte repeated c - 126 times"""
+    out = bytearray()
+    literal = bytearray()
+    i = 0
+    while i < len(data):
+        run = 1
+        while i + run < len(data) and data[i + run] == data[i] and run < 129:
+            run += 1
+        if run >= 3 or (run == 2 and not literal):
+            if literal:
+                out += bytes([len(literal) - 1]) + literal
+                literal = bytearray()
+            out += bytes([run + 126, data[i]])
+            i += run
+        else:
+            literal.append(data[i])
+            i += 1
+            if len(literal) == 128:
+                out += bytes([127]) + literal
+                literal = bytearray()
+    if literal:
+        out += bytes([len(literal) - 1]) + literal
+    return bytes(out)
+
+
+def rle_decode(data):
+    """a page decoded, zero padding after the encoded page is decoded as zeros"""
+    out = bytearray()
+    i = 0
+    while i + 1 < len(data):
+        if data[i] < 128:
+            out += data[i + 1:i + 2 + data[i]]
+            i += 2 + data[i]
+        else:
+            out += bytes([data[i + 1]]) * (data[i] - 126)
+            i += 2
+    return bytes(out)
+
+
+def spans(offset, size, boundary):
+    """size bytes at offset are in two blocks of boundary bytes or more"""
+    return offset // boundary != (offset + size - 1) // boundary
+
+
+def padding_rows(offset, row_size, num_rows, boundary=ERASE_SECTOR_SIZE):
+    """rows of zeros to write at offset so the num_rows rows that follow do
+    not span an erase sector, or a block of boundary bytes, none when they
+    would not fit in one anyway.  Rows that do not divide the block start
+    after the next one by up to a row, so the first offset that fits is
+    looked for row by row, over the blocks until they start at the same
+    offset again"""
+    size = row_size * num_rows
+    if size > boundary:
+        return 0
+    for padding in range(boundary // math.gcd(row_size, boundary)):
+        if not spans(offset + row_size * padding, size, boundary):
+            return padding
+    return 0
+
+
+def align_rows(offset, row_size, num_rows, bank_size=0):
+    """padding rows before a puzzle so it does not span an erase sector nor,
+    with a bank_size, a bank of a rom split in several files.  Moving it out
+    of a bank may make it span a sector again, so until neither needs more"""
+    padding = 0
+    while True:
+        more = padding_rows(offset + row_size * padding, row_size, num_rows)
+        if bank_size:
+            more += padding_rows(offset + row_size * (padding + more), row_size, num_rows, bank_size)
+        if not more:
+            return padding
+        padding += more
+
+
+def index_offset(data_size):
+    """the index starts after the pages, aligned to 4 bytes"""
+    return (data_size + 3) // 4 * 4
+
+
+class Rom:
+    def __init__(self, path):
+        self.data = open(path, 'rb').read()
+        self.config_offset = len(self.data) - CONFIG_SECTOR_SIZE
+        self.config = ConfigSector.decode(self.data[self.config_offset:])
+        # pages actually read, verify lowers them when the config is wrong
+        self.num_pages = self.config.num_pages
+        self.type_pages = list(self.config.type_pages)
+        # roms made before there were several types only have type0 pages
+        if not any(self.type_pages):
+            self.type_pages[0] = self.num_pages
+
+    def data_size(self):
+        """bytes taken by the pages of all types"""
+        return sum(pages * size for pages, size in zip(self.type_pages, self.config.sizes))
+
+    def crc32(self):
+        """CRC32 of the pages, to compare with data_crc32"""
+        return zlib.crc32(self.data[:self.data_size()])
+
+    def page_type(self, page):
+        """type slot of a page"""
+        for slot, pages in enumerate(self.type_pages):
+            if page < pages:
+                return slot
+            page -= pages
+        return 0
+
+    def page_offset(self, page):
+        """offset of a page in the rom, pages of a type are after those of the
+        previous types"""
+        offset = 0
+        for pages, size in zip(self.type_pages, self.config.sizes):
+            if page < pages:
+                return offset + page * size
+            offset += pages * size
+            page -= pages
+        return offset
+
+    def page_data(self, page):
+        """the bytes of a page"""
+        if page >= sum(self.type_pages):
+            return b''
+        offset = self.page_offset(page)
+        return self.data[offset:offset + self.config.sizes[self.page_type(page)]]
+
+    def packed(self, page):
+        """the page is a binary puzzle row"""
+        return self.config.flags & FLAG_PACKED_BOARD and self.config.types[self.page_type(page)] == CHESS_PUZZLE
+
+    def padding(self, page):
+        """the page is left empty so the next puzzle starts on an erase sector"""
+        return self.config.flags & FLAG_SECTOR_ALIGN and not any(self.page_data(page))
+
+    def meta_size(self, page):
+        """bytes of difficulty and theme bits at the end of a page"""
+        if self.config.flags & FLAG_ROW_META and self.config.types[self.page_type(page)] == CHESS_PUZZLE:
+            return ROW_META.size
+        return 0
+
+    def id_size(self, page):
+        """bytes of puzzle id at the start of a page"""
+        if self.config.flags & FLAG_PUZZLE_ID and self.config.types[self.page_type(page)] == CHESS_PUZZLE:
+            return PUZZLE_ID_SIZE
+        return 0
+
+    def puzzle_id(self, page):
+        """the id at the start of a puzzle page, None without FLAG_PUZZLE_ID"""
+        if not self.id_size(page):
+            return None
+        return self.page_data(page)[:PUZZLE_ID_SIZE].rstrip(b'\0').decode('ascii', 'replace')
+
+    def meta(self, page):
+        """difficulty and themes of a puzzle page, None without FLAG_ROW_META"""
+        if not self.meta_size(page):
+            return None
+        data = self.page_data(page)
+        if self.config.flags & FLAG_PAGE_CHECKSUM:
+            data = data[:-1]
+        difficulty, bits = ROW_META.unpack(data[-ROW_META.size:])
+        return difficulty, theme_names(bits)
+
+    def content(self, page):
+        """the bytes of a page without checksum, id and difficulty, decoded"""
+        data = self.page_data(page)
+        if self.config.flags & FLAG_PAGE_CHECKSUM:
+            data = data[:-1]
+        if self.meta_size(page):
+            data = data[:-ROW_META.size]
+        data = data[self.id_size(page):]
+        if self.config.flags & FLAG_RLE:
+            data = rle_decode(data)
+        return data
+
+    def text(self, page):
+        """the text of a page as stored, without newline and padding"""
+        data = self.content(page)
+        if self.packed(page):
+            return unpack_row(data)
+        return data.split(b'\n')[0].rstrip(b'\0').decode('ascii', 'replace')
+
+    def templated(self, page):
+        """the page is a puzzle row laid out as the row template"""
+        return (self.config.flags & FLAG_ROW_TEMPLATE and not self.packed(page)
+                and self.config.types[self.page_type(page)] == CHESS_PUZZLE)
+
+    def row(self, page):
+        """the text of a page, puzzle rows with the default fields"""
+        if self.templated(page):
+            return parse_row(self.text(page), self.config.row_template)[0]
+        return self.text(page)
+
+    def rating(self, page):
+        """the rating of a puzzle page, when the row template has it"""
+        if self.templated(page):
+            return parse_row(self.text(page), self.config.row_template)[1]
+        return None
+
+    def rows(self):
+        """the chess puzzle pages"""
+        for page in range(self.num_pages):
+            if self.config.types[self.page_type(page)] == CHESS_PUZZLE and not self.padding(page):
+                yield self.row(page)
+
+    def index(self):
+        """id, first_page, num_pages and rating of every puzzle in the
+        index, None when there is no index"""
+        if not self.config.flags & FLAG_INDEX:
+            return None
+        entries = []
+        for i in range(self.config.index_count):
+            puzzle_id, first_page, num_pages, rating = INDEX_ENTRY.unpack_from(
+                self.data, self.config.index_offset + i * INDEX_ENTRY.size)
+            entries.append((puzzle_id.rstrip(b'\0').decode('ascii', 'replace'), first_page, num_pages, rating))
+        return entries
+
+    def end(self):
+        """end of the pages, and of the index when there is one"""
+        if self.config.flags & FLAG_INDEX and self.config.index_count:
+            return self.config.index_offset + self.config.index_count * INDEX_ENTRY.size
+        return self.data_size()
+
+    def free_end(self):
+        """end of the space the pages can take, the progress region or the
+        config sector"""
+        return self.config.progress_offset if self.config.progress_sectors else self.config_offset
+
+    def fill_byte(self):
+        """value of the free space after the pages, None when there is none"""
+        return self.data[self.end()] if self.end() < self.free_end() else None
+
+    def puzzles(self):
+        """the rows of every puzzle, by id"""
+        puzzles = {}
+        for row in self.rows():
+            puzzles.setdefault(row.split(',')[0], []).append(row)
+        return puzzles
+
+
+def decode(row):
+    """id, board, move, page and total of a puzzle row.  The board is seen
+    from the solver side, the move is a pair of indices on it"""
+    puzzle_id, efen, move_from, move_to, page, total = row.split(',')
+    return puzzle_id, efen, (int(move_from), int(move_to)), int(page), int(total)
+
+
+def board(efen):
+    return '\n'.join('  ' + efen[i:i + 8].replace('1', '.') for i in range(0, 64, 8))
+
+
+def layout(rom):
+    """start, end and what is there of every region of the rom, in order"""
+    regions = []
+    sections = list(rom.config.sections)
+    # roms without a section table have a section per type
+    if not sections:
+        first_page = 0
+        for pages in rom.type_pages:
+            if pages:
+                sections.append((first_page, pages))
+            first_page += pages
+    for i, (first_page, num_pages) in enumerate(sections):
+        if num_pages:
+            regions.append((rom.page_offset(first_page), rom.page_offset(first_page + num_pages),
+                            'section %d, %s pages %d to %d' % (
+                                i, TYPES.get(rom.config.types[rom.page_type(first_page)], 'type'),
+                                first_page, first_page + num_pages - 1)))
+    if rom.config.flags & FLAG_INDEX and rom.config.index_count:
+        regions.append((rom.config.index_offset, rom.end(), 'index of %d puzzles' % rom.config.index_count))
+    if rom.fill_byte() is not None:
+        regions.append((rom.end(), rom.free_end(), 'free, 0x%02x' % rom.fill_byte()))
+    if rom.config.progress_sectors:
+        regions.append((rom.config.progress_offset, rom.config_offset,
+                        'progress region, %d sectors' % rom.config.progress_sectors))
+    regions.append((rom.config_offset, len(rom.data), 'config sector'))
+    # what is between the regions, e.g. the alignment of the index
+    regions.sort()
+    gaps = [(end, start, 'padding') for (_, end, _), (start, _, _) in zip(regions, regions[1:]) if start > end]
+    return sorted(regions + gaps)
+
+
+def inspect(args):
+    rom = Rom(args.rom)
+    if args.map:
+        for start, end, what in layout(rom):
+            print('0x%08x-0x%08x %10d bytes  %s' % (start, end - 1, end - start, what))
+        return
+    print('size:        %d bytes' % len(rom.data))
+    print('magic:       0x%08x%s' % (rom.config.magic, '' if rom.config.magic == MAGIC else ' (wrong)'))
+    if rom.config.byte_order != 'little':
+        print('byte order:  %s endian' % rom.config.byte_order)
+    print('num_pages:   %d' % rom.num_pages)
+    print('total_size:  %d bytes' % rom.config.total_size)
+    print('num_types:   %d' % rom.config.num_types)
+    print('font_size:   %d' % rom.config.font_size)
+    print('version:     %d' % rom.config.version)
+    print('flags:       0x%02x%s' % (rom.config.flags, ''.join(
+        ', ' + FLAGS.get(flag, ('unknown',))[0] for flag in (1 << i for i in range(8)) if rom.config.flags & flag)))
+    for i in range(4):
+        print('type%d:       %s, %d bytes, %d pages' % (
+            i, TYPES.get(rom.config.types[i], rom.config.types[i]), rom.config.sizes[i], rom.type_pages[i]))
+    for i, (first_page, num_pages) in enumerate(rom.config.sections):
+        print('section %d:   pages %d to %d, %s' % (i, first_page, first_page + num_pages - 1,
+                                                 TYPES.get(rom.config.types[rom.page_type(first_page)])))
+    if rom.config.build_time:
+        print('built:       %s UTC, tools %s, commit %s' % (
+            time.strftime('%Y-%m-%d %H:%M:%S', time.gmtime(rom.config.build_time)), rom.config.tool_version or '?',
+            rom.config.git_commit.hex() if any(rom.config.git_commit) else 'unknown'))
+    else:
+        print('built:       unknown, rom made before it was recorded')
+    if any(rom.config.filters_sha256):
+        print('filters:     sha256 %s' % rom.config.filters_sha256.hex())
+    if rom.config.row_template:
+        print('row:         %s' % rom.config.row_template)
+    print('data_crc32:  0x%08x%s' % (rom.config.data_crc32, '' if rom.config.data_crc32 in (0, rom.crc32()) else ' (wrong)'))
+    if rom.fill_byte() is not None:
+        print('free:        %d bytes of 0x%02x' % (rom.free_end() - rom.end(), rom.fill_byte()))
+    if rom.config.progress_sectors:
+        print('progress:    %d sectors at %d' % (rom.config.progress_sectors, rom.config.progress_offset))
+    index = rom.index()
+    if index is None:
+        print('puzzles:     %d' % len(rom.puzzles()))
+    else:
+        print('index:       %d puzzles at %d' % (rom.config.index_count, rom.config.index_offset))
+        ratings = [entry[3] for entry in index]
+        if ratings:
+            print('ratings:     %d to %d' % (min(ratings), max(ratings)))
+    for page in range(min(args.rows, rom.num_pages)):
+        if rom.padding(page):
+            print('\npage %d: padding' % page)
+            continue
+        if rom.config.types[rom.page_type(page)] != CHESS_PUZZLE:
+            print('\npage %d: %s' % (page, rom.row(page)))
+            continue
+        puzzle_id, efen, move, page_number, total = decode(rom.row(page))
+        print('\npage %d: puzzle %s, %d of %d, move %s' % (
+            page, puzzle_id, page_number, total, '-' if move[0] == move[1] else '%d-%d' % move))
+        if rom.meta(page):
+            print('difficulty %d, themes %s' % (rom.meta(page)[0], ' '.join(rom.meta(page)[1]) or '-'))
+        print(board(efen))
+
+
+def unpack(args):
+    """write the pages back as a puzzles directory fenpuzzles_toflash.sh can
+    read, one per section.  The themes are only in roms with FLAG_ROW_META,
+    written to index.csv, the rating in those with an index or a row template
+    with it, else it is rounded down from the difficulty or 0.  Pages are
+    named puzzle-<id>-<rating>-rom-<page>.
+    Text sections are written to text.txt"""
+    rom = Rom(args.rom)
+    ratings = {entry[0]: entry[3] for entry in rom.index() or []}
+    sections = rom.config.sections or [(0, rom.num_pages)]
+    for i, (first_page, num_pages) in enumerate(sections):
+        directory = args.dir if len(sections) == 1 else os.path.join(args.dir, 'section%02d' % i)
+        os.makedirs(directory, exist_ok=True)
+        if rom.config.types[rom.page_type(first_page)] == TEXT:
+            with open(os.path.join(directory, 'text.txt'), 'w') as text:
+                for page in range(first_page, first_page + num_pages):
+                    text.write(rom.row(page) + '\n')
+            print('%s: %d pages' % (directory, num_pages))
+            continue
+        index = []
+        with open(os.path.join(directory, 'pages.csv'), 'w') as pages:
+            for page in range(first_page, first_page + num_pages):
+                if rom.padding(page):
+                    continue
+                row = rom.row(page)
+                puzzle_id, _, _, page_number, _ = decode(row)
+                meta = rom.meta(page)
+                rating = ratings.get(puzzle_id, rom.rating(page) or (meta[0] * 16 if meta else 0))
+                name = 'puzzle-%s-%d-rom-%02d' % (puzzle_id, rating, page_number)
+                pages.write('%s,%s\n' % (name, row))
+                if meta and page_number == 1:
+                    index.append('%s,%d,%s\n' % (name[:-len('-01')], rating, ' '.join(meta[1])))
+                if args.page_files:
+                    with open(os.path.join(directory, name + '.txt'), 'w') as f:
+                        f.write(row + '\n')
+        if index:
+            with open(os.path.join(directory, 'index.csv'), 'w') as f:
+                f.writelines(index)
+        print('%s: %d pages' % (directory, num_pages))
+
+
+def row_errors(row):
+    """what is wrong with a puzzle row, empty when it is fine"""
+    fields = row.split(',')
+    if len(fields) != 6:
+        return ['%d fields instead of 6' % len(fields)]
+    puzzle_id, efen, move_from, move_to, page, total = fields
+    errors = []
+    if not puzzle_id:
+        errors.append('no puzzle id')
+    if len(efen) != 64 or not set(efen) <= PIECES:
+        errors.append('invalid board %s' % efen)
+    elif efen.count('K') != 1 or efen.count('k') != 1:
+        errors.append('not one king of each color')
+    elif set(efen[:8] + efen[56:]) & set('pP'):
+        errors.append('pawn on the first or last rank')
+    if not all(f.isdigit() for f in (move_from, move_to, page, total)):
+        errors.append('invalid numbers')
+    elif int(move_from) > 63 or int(move_to) > 63:
+        errors.append('move out of the board')
+    elif not 1 <= int(page) <= int(total):
+        errors.append('page %s of %s' % (page, total))
+    return errors
+
+
+def verify(args):
+    """check the rom is as fenpuzzles_toflash.sh writes it, exit 1 if not"""
+    rom = Rom(args.rom)
+    errors = []
+    if rom.config.magic != MAGIC:
+        errors.append('config: magic is 0x%08x instead of 0x%08x' % (rom.config.magic, MAGIC))
+    if rom.config.version > FORMAT_VERSION:
+        errors.append('config: version %d, newer than the known %d' % (rom.config.version, FORMAT_VERSION))
+    for flag in (1 << i for i in range(8)):
+        if rom.config.flags & flag and flag not in FLAGS:
+            errors.append('config: unknown flag 0x%02x' % flag)
+        elif rom.config.flags & flag and rom.config.version < FLAGS[flag][1]:
+            errors.append('config: %s needs version %d, not %d' % (
+                FLAGS[flag][0], FLAGS[flag][1], rom.config.version))
+    if rom.config.flags & FLAG_ROW_TEMPLATE:
+        for slot, page_type in enumerate(rom.config.types):
+            if page_type == CHESS_PUZZLE:
+                errors += ['config: row template %s' % e
+                           for e in row_template_errors(rom.config.row_template, rom.config.sizes[slot])]
+    elif rom.config.row_template:
+        errors.append('config: row template set without its flag')
+    for i in range(4):
+        if rom.type_pages[i] and not rom.config.sizes[i]:
+            errors.append('config: type%d has pages but size%d is 0' % (i, i))
+            rom.type_pages[i] = 0
+    if sum(rom.type_pages) != rom.num_pages:
+        errors.append('config: %d pages of all types, num_pages is %d' % (sum(rom.type_pages), rom.num_pages))
+        rom.num_pages = min(rom.num_pages, sum(rom.type_pages))
+    if rom.config.num_types != len([t for t in rom.config.types if t]):
+        errors.append('config: num_types is %d, %d types are used' % (
+            rom.config.num_types, len([t for t in rom.config.types if t])))
+    if rom.config.total_size != rom.data_size():
+        errors.append('config: total_size %d is not the size of the pages (%d)' % (
+            rom.config.total_size, rom.data_size()))
+    if rom.data_size() > rom.free_end():
+        errors.append('config: %d pages do not fit before the %s' % (
+            rom.num_pages, 'progress region' if rom.config.progress_sectors else 'config sector'))
+        rom.num_pages = 0
+    # sections follow each other and cover all the pages
+    next_page = 0
+    for i, (first_page, num_pages) in enumerate(rom.config.sections):
+        if first_page != next_page:
+            errors.append('config: section %d starts at page %d instead of %d' % (i, first_page, next_page))
+        next_page = first_page + num_pages
+    if rom.config.sections and next_page != rom.num_pages:
+        errors.append('config: sections end at page %d, there are %d pages' % (next_page, rom.num_pages))
+    # anything the fields do not account for is not written back
+    if rom.config.encode() != rom.data[rom.config_offset:]:
+        errors.append('config: bytes set outside of its fields')
+
+    previous = None
+    for page in range(rom.num_pages):
+        # padding pages are zeros, the puzzle before is checked against the one after
+        if rom.padding(page):
+            continue
+        data = rom.page_data(page)
+        if rom.config.flags & FLAG_PAGE_CHECKSUM and data[-1] != sum(data[:-1]) & 0xff:
+            errors.append('page %d: wrong checksum' % page)
+        data = rom.content(page)
+        if rom.packed(page):
+            end = PACKED_ROW.size + data[PACKED_ROW.size - 1]
+            if end > len(data):
+                errors.append('page %d: id longer than the page' % page)
+            elif any(data[end:]):
+                errors.append('page %d: padding is not zero' % page)
+            row = unpack_row(data)
+        else:
+            text, newline, padding = data.partition(b'\n')
+            if not newline:
+                errors.append('page %d: no newline' % page)
+            elif any(padding):
+                errors.append('page %d: padding is not zero' % page)
+            row = text.decode('ascii', 'replace')
+        if rom.config.types[rom.page_type(page)] != CHESS_PUZZLE:
+            continue
+        if rom.templated(page):
+            row = parse_row(row, rom.config.row_template)[0]
+        row_errors_found = row_errors(row)
+        errors += ['page %d: %s' % (page, e) for e in row_errors_found]
+        if row_errors_found:
+            # the next page cannot be checked against this one
+            previous = False
+            continue
+        puzzle_id, _, _, page_number, total = decode(row)
+        if rom.id_size(page) and rom.puzzle_id(page) != puzzle_id:
+            errors.append('page %d: id field %s, the page is of puzzle %s' % (page, rom.puzzle_id(page), puzzle_id))
+        # the pages of an aligned puzzle are read from a single sector, and a single bank
+        if page_number == 1 and rom.config.flags & FLAG_SECTOR_ALIGN:
+            size = total * rom.config.sizes[rom.page_type(page)]
+            for boundary, name in ((ERASE_SECTOR_SIZE, 'erase sector'), (args.bank_size, 'bank')):
+                if boundary and size <= boundary and spans(rom.page_offset(page), size, boundary):
+                    errors.append('page %d: puzzle %s spans two %ss' % (page, puzzle_id, name))
+        # pages of a puzzle are one after the other, from 1 to total
+        expected = 1
+        if previous and previous[0] == puzzle_id and previous[1] < previous[2]:
+            expected = previous[1] + 1
+        if previous is not False and page_number != expected:
+            errors.append('page %d: puzzle %s page %d, expected page %d' % (
+                page, puzzle_id, page_number, expected))
+        previous = (puzzle_id, page_number, total)
+    if previous and previous[1] != previous[2]:
+        errors.append('page %d: puzzle %s ends at page %d of %d' % (
+            rom.num_pages - 1, previous[0], previous[1], previous[2]))
+    index = rom.index()
+    if index is not None:
+        if rom.config.index_count and rom.config.index_offset < rom.data_size():
+            errors.append('index: at %d, before the end of the pages' % rom.config.index_offset)
+        if rom.end() > rom.free_end():
+            errors.append('index: does not fit before the %s' % (
+                'progress region' if rom.config.progress_sectors else 'config sector'))
+            index = []
+        # id, first page and number of pages of every puzzle, from its rows.
+        # A puzzle starts again where its page 1 is
+        puzzles = []
+        for page in range(rom.num_pages):
+            if rom.config.types[rom.page_type(page)] == CHESS_PUZZLE and not rom.padding(page):
+                fields = rom.row(page).split(',')
+                if not puzzles or puzzles[-1][0] != fields[0] or fields[-2:-1] == ['1']:
+                    puzzles.append([fields[0], page, 0])
+                puzzles[-1][2] += 1
+        puzzles = set(tuple(p) for p in puzzles)
+        for puzzle_id, first_page, num_pages, _ in index:
+            if (puzzle_id, first_page, num_pages) not in puzzles:
+                errors.append('index: puzzle %s is not at pages %d to %d' % (
+                    puzzle_id, first_page, first_page + num_pages - 1))
+            puzzles.discard((puzzle_id, first_page, num_pages))
+        errors += ['index: puzzle %s at page %d missing' % (p[0], p[1]) for p in sorted(puzzles)]
+        if any(rom.data[rom.data_size():rom.config.index_offset]):
+            errors.append('data: not zero between the pages and the index')
+    # the progress region is written by the firmware, anything can be in it
+    if rom.config.progress_sectors and (
+            rom.config.progress_offset % CONFIG_SECTOR_SIZE or rom.config.progress_offset
+            + rom.config.progress_sectors * CONFIG_SECTOR_SIZE > rom.config_offset):
+        errors.append('config: progress region of %d sectors at %d is not in whole sectors before the config sector'
+                      % (rom.config.progress_sectors, rom.config.progress_offset))
+    free = rom.data[rom.end():rom.free_end()]
+    if free.strip(free[:1]):
+        errors.append('data: free space after the last page is not all the same byte')
+    if not rom.config.data_crc32:
+        print('data: no CRC32, rom made before it was added, not checked')
+    elif rom.config.data_crc32 != rom.crc32():
+        errors.append('data: CRC32 is 0x%08x instead of 0x%08x' % (rom.crc32(), rom.config.data_crc32))
+
+    for error in errors[:MAX_ERRORS]:
+        print(error)
+    if len(errors) > MAX_ERRORS:
+        print('... %d more errors' % (len(errors) - MAX_ERRORS))
+    print('%s: %s' % (args.rom, 'ok' if not errors else '%d errors' % len(errors)))
+    return 1 if errors else 0
+
+
+def manifest(args):
+    """write a JSON manifest of the rom, with its SHA-256 and the section,
+    pages and rating, when the rom has it, of every puzzle"""
+    rom = Rom(args.rom)
+    ratings = {entry[0]: entry[3] for entry in rom.index() or []}
+    puzzles = []
+    sections = rom.config.sections or [(0, rom.num_pages)]
+    for section, (first_page, num_pages) in enumerate(sections):
+        for page in range(first_page, first_page + num_pages):
+            if rom.config.types[rom.page_type(page)] != CHESS_PUZZLE or rom.padding(page):
+                continue
+            puzzle_id, _, _, page_number, total = decode(rom.row(page))
+            if page_number == 1:
+                puzzles.append({'id': puzzle_id, 'section': section, 'first_page': page,
+                                'last_page': page + total - 1,
+                                'rating': ratings.get(puzzle_id, rom.rating(page))})
+    with open(args.output, 'w') as out:
+        json.dump({'rom': os.path.basename(args.rom),
+                   'size': len(rom.data),
+                   'sha256': hashlib.sha256(rom.data).hexdigest(),
+                   'data_crc32': '0x%08x' % rom.config.data_crc32,
+                   'puzzles': puzzles}, out, indent=2)
+    print('%s: %d puzzles' % (args.output, len(puzzles)))
+
+
+def diff(args):
+    """puzzles added, removed and changed from one rom to the other, exit 1
+    when there is any difference"""
+    old, new = Rom(args.old).puzzles(), Rom(args.new).puzzles()
+    added = sorted(set(new) - set(old))
+    removed = sorted(set(old) - set(new))
+    changed = sorted(p for p in set(old) & set(new) if old[p] != new[p])
+    for puzzle_id in removed:
+        print('- %s' % puzzle_id)
+    for puzzle_id in added:
+        print('+ %s' % puzzle_id)
+    for puzzle_id in changed:
+        print('~ %s (%d pages, was %d)' % (puzzle_id, len(new[puzzle_id]), len(old[puzzle_id])))
+    moved = []
+    if args.order:
+        # position of every puzzle in the rom, dicts keep the rom order
+        old_position = {p: i for i, p in enumerate(old)}
+        new_position = {p: i for i, p in enumerate(new)}
+        moved = [p for p in old if p in new and p not in changed and old_position[p] != new_position[p]]
+    for puzzle_id in moved:
+        print('> %s moved from %d to %d' % (puzzle_id, old_position[puzzle_id], new_position[puzzle_id]))
+    print('%d added, %d removed, %d changed, %d unchanged' % (
+        len(added), len(removed), len(changed), len(set(old) & set(new)) - len(changed)))
+    return 1 if added or removed or changed or moved else 0
+
+
+def merge(args):
+    """pages of the roms one after the other, sections of every rom are kept
+    but grouped by type, as the pages of a type are stored together.  Empty
+    sections are dropped.  The index, if the roms have one, is made again, and
+    the padding of sector aligned roms too as the puzzles move.  The progress
+    region is erased, the pages it was about moved.  The filters SHA-256 is
+    the one of the roms, or a SHA-256 of theirs when they differ.  With a
+    bank size puzzles of sector aligned roms are kept within banks too"""
+    roms = [Rom(path) for path in args.roms]
+    indexes = {rom: rom.index() or [] for rom in roms}
+    flash_size = len(roms[0].data) if args.flash_size is None else args.flash_size
+    progress_sectors = args.progress_sectors
+    if progress_sectors is None:
+        progress_sectors = max(rom.config.progress_sectors for rom in roms)
+    progress_offset = flash_size - CONFIG_SECTOR_SIZE * (progress_sectors + 1)
+    # type -> row size and (rom, first_page, num_pages) of every section
+    types = {}
+    for path, rom in zip(args.roms, roms):
+        if rom.config.magic != MAGIC:
+            sys.exit('%s: not a rom, magic is 0x%08x' % (path, rom.config.magic))
+        if rom.config.flags != roms[0].config.flags:
+            sys.exit('%s: config flags 0x%02x, %s has 0x%02x' % (
+                path, rom.config.flags, args.roms[0], roms[0].config.flags))
+        if rom.config.row_template != roms[0].config.row_template:
+            sys.exit('%s: row template %s, %s has %s' % (
+                path, rom.config.row_template, args.roms[0], roms[0].config.row_template))
+        sections = rom.config.sections
+        # sections without a table are one per type
+        if not sections:
+            sections, first_page = [], 0
+            for pages in rom.type_pages:
+                if pages:
+                    sections.append((first_page, pages))
+                first_page += pages
+        for first_page, num_pages in sections:
+            if not num_pages:
+                continue
+            slot = rom.page_type(first_page)
+            page_type, size = rom.config.types[slot], rom.config.sizes[slot]
+            row_size, type_sections = types.setdefault(page_type, (size, []))
+            if size != row_size:
+                sys.exit('%s: %s pages of %d bytes, other roms have %d' % (
+                    path, TYPES.get(page_type, page_type), size, row_size))
+            type_sections.append((rom, first_page, num_pages))
+    if len(types) > 4:
+        sys.exit('%d types of pages, at most 4 fit in the config sector' % len(types))
+    if sum(len(t[1]) for t in types.values()) > MAX_SECTIONS:
+        sys.exit('%d sections, at most %d fit in the config sector' % (
+            sum(len(t[1]) for t in types.values()), MAX_SECTIONS))
+
+    data = bytearray()
+    sections = []
+    type_slots = []
+    index = []
+    align = roms[0].config.flags & FLAG_SECTOR_ALIGN
+    for page_type, (row_size, type_sections) in types.items():
+        type_offset, type_first_page = len(data), sum(t[2] for t in type_slots)
+        for rom, first_page, num_pages in type_sections:
+            section_first_page = type_first_page + (len(data) - type_offset) // row_size
+            # page in the merged rom of every page of the section
+            new_pages = {}
+            for page in range(first_page, first_page + num_pages):
+                if rom.padding(page):
+                    continue
+                if align and page_type == CHESS_PUZZLE:
+                    _, _, _, page_number, total = decode(rom.row(page))
+                    if page_number == 1:
+                        data += bytes(row_size * align_rows(len(data), row_size, total, args.bank_size))
+                new_pages[page] = type_first_page + (len(data) - type_offset) // row_size
+                data += rom.page_data(page)
+            for puzzle_id, puzzle_page, puzzle_pages, rating in indexes[rom]:
+                if puzzle_page in new_pages:
+                    index.append(INDEX_ENTRY.pack(puzzle_id.encode(), new_pages[puzzle_page],
+                                                  puzzle_pages, rating))
+            sections.append((section_first_page,
+                             type_first_page + (len(data) - type_offset) // row_size - section_first_page))
+        pages = (len(data) - type_offset) // row_size
+        type_slots.append((page_type, row_size, pages))
+    pages_size = len(data)
+    crc = zlib.crc32(data)
+    if index:
+        data += b'\0' * (index_offset(pages_size) - pages_size) + b''.join(index)
+    if len(data) > progress_offset:
+        sys.exit('%d bytes of pages do not fit a flash of %d bytes' % (len(data), flash_size))
+    puzzles = [set(rom.puzzles()) for rom in roms]
+    duplicates = sum(len(p) for p in puzzles) - len(set().union(*puzzles))
+    if duplicates:
+        print('%d puzzles are in more than one rom' % duplicates, file=sys.stderr)
+    # the filters of every rom, or their common ones
+    filters = []
+    for rom in roms:
+        if any(rom.config.filters_sha256) and rom.config.filters_sha256 not in filters:
+            filters.append(rom.config.filters_sha256)
+    filters = filters[0] if len(filters) == 1 else hashlib.sha256(b''.join(filters)).digest() if filters else bytes(32)
+
+    with open(args.output, 'wb') as out:
+        out.write(data.ljust(progress_offset, bytes([args.fill_byte])))
+        out.write(b'\xff' * CONFIG_SECTOR_SIZE * progress_sectors)
+        config = ConfigSector.build(type_slots, sections, crc,
+                                    roms[0].config.font_size, max(rom.config.version for rom in roms),
+                                    roms[0].config.flags, index_offset(pages_size) if index else 0, len(index),
+                                    progress_offset if progress_sectors else 0, progress_sectors, filters,
+                                    roms[0].config.row_template, roms[0].config.byte_order)
+        out.write(config.encode())
+    print('%s: %d pages in %d sections' % (args.output, config.num_pages, len(sections)))
+
+
+def uf2(data, address):
+    """data as UF2 blocks, the first one to be written at address"""
+    num_blocks = (len(data) + UF2_PAYLOAD_SIZE - 1) // UF2_PAYLOAD_SIZE
+    for block in range(num_blocks):
+        payload = data[block * UF2_PAYLOAD_SIZE:(block + 1) * UF2_PAYLOAD_SIZE]
+        yield UF2_BLOCK.pack(UF2_MAGIC[0], UF2_MAGIC[1], UF2_FLAG_FAMILY_ID, address + block * UF2_PAYLOAD_SIZE,
+                             UF2_PAYLOAD_SIZE, block, num_blocks, UF2_RP2040, payload, UF2_MAGIC[2])
+
+
+def srec_record(record_type, address, data, address_size):
+    fields = bytes([address_size + len(data) + 1]) + address.to_bytes(address_size, 'big') + data
+    return 'S%d%s%02X\n' % (record_type, fields.hex().upper(), ~sum(fields) & 0xff)
+
+
+def srec(data, address, name='lightnote'):
+    """data as Motorola S-records, the first byte at address.  S1, S2 or S3
+    data records as the last address needs, no count record"""
+    end = address + len(data) - 1
+    data_type, address_size = (1, 2) if end <= 0xffff else (2, 3) if end <= 0xffffff else (3, 4)
+    yield srec_record(0, 0, name.encode(), 2)
+    for offset in range(0, len(data), SREC_DATA_SIZE):
+        yield srec_record(data_type, address + offset, data[offset:offset + SREC_DATA_SIZE], address_size)
+    # the termination record of the data records, S9 after S1 and so on
+    yield srec_record(10 - data_type, 0, b'', address_size)
+
+
+def convert(args):
+    """write the rom in another file format, for flashing without a
+    programmer reading the raw binary.  uf2 files start at the RP2040 flash
+    by default, S-records at 0, the start of the flash chip"""
+    data = open(args.rom, 'rb').read()
+    address = args.address
+    if address is None:
+        address = FLASH_ADDRESS if args.format == 'uf2' else 0
+    with open(args.output, 'wb') as out:
+        if args.format == 'uf2':
+            for block in uf2(data, address):
+                out.write(block)
+        else:
+            for record in srec(data, address, os.path.basename(args.rom)):
+                out.write(record.encode())
+    print('%s: %d bytes from 0x%08x' % (args.output, len(data), address))
+
+
+def size(text):
+    """a size with an optional K or M suffix, as to_bytes in functions.sh"""
+    units = {'k': 1024, 'm': 1024 * 1024}
+    if text[-1:].lower() in units:
+        return int(text[:-1]) * units[text[-1].lower()]
+    return int(text)
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.split('\n')[0])
+    commands = parser.add_subparsers(dest='command', required=True)
+    command = commands.add_parser('inspect', help='print the config sector and a few pages')
+    command.add_argument('rom')
+    command.add_argument('--rows', type=int, default=3, help='number of pages to print')
+    command.add_argument('--map', action='store_true',
+                         help='print where the sections, the index, the progress region and the config sector are instead')
+    command.set_defaults(run=inspect)
+    command = commands.add_parser('unpack', help='write the pages back to pages.csv')
+    command.add_argument('rom')
+    command.add_argument('dir', nargs='?', default='unpacked')
+    command.add_argument('--page-files', action='store_true', help='also write one text file per page')
+    command.set_defaults(run=unpack)
+    command = commands.add_parser('verify', help='check the rom, exit 1 if anything is wrong')
+    command.add_argument('rom')
+    command.add_argument('--bank-size', type=size, default=0,
+                         help='also check the puzzles of sector aligned roms are within banks of this size')
+    command.set_defaults(run=verify)
+    command = commands.add_parser('diff', help='puzzles added, removed or changed between two roms')
+    command.add_argument('old')
+    command.add_argument('new')
+    command.add_argument('--order', action='store_true', help='also report puzzles at another position')
+    command.set_defaults(run=diff)
+    command = commands.add_parser('manifest', help='write a JSON manifest of the puzzles of the rom')
+    command.add_argument('rom')
+    command.add_argument('-o', '--output', default='manifest.json')
+    command.set_defaults(run=manifest)
+    command = commands.add_parser('merge', help='one rom with the pages of several roms')
+    command.add_argument('roms', nargs='+')
+    command.add_argument('-o', '--output', default='merged.rom')
+    command.add_argument('--flash-size', type=size, help='size of the new rom (default the size of the first one)')
+    command.add_argument('--fill-byte', type=lambda text: int(text, 0), default=0xff,
+                         help='value of the free space after the pages (default 0xff)')
+    command.add_argument('--progress-sectors', type=int,
+                         help='sectors kept for the firmware before the config sector (default as the roms)')
+    command.add_argument('--bank-size', type=size, default=0,
+                         help='keep the puzzles of sector aligned roms within banks of this size, see --split-size')
+    command.set_defaults(run=merge)
+    command = commands.add_parser('convert', help='write the rom as uf2, to flash it from the bootloader drive, '
+                                  'or as S-records for universal programmers')
+    command.add_argument('rom')
+    command.add_argument('output')
+    command.add_argument('--format', choices=['uf2', 'srec'], default='uf2')
+    command.add_argument('--address', type=lambda text: int(text, 0),
+                         help='address the rom is flashed at (default 0x%08x, the start of the RP2040 flash, '
+                         'for uf2 and 0 for srec)' % FLASH_ADDRESS)
+    command.set_defaults(run=convert)
+    args = parser.parse_args()
+    sys.exit(args.run(args))
+
+
+if __name__ == '__main__':
+    main()
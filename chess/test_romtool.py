@@ -0,0 +1,170 @@
+#! /usr/bin/env python
+"""Tests of romtool.py, and of the roms of fenpuzzles_toflash.sh and genfenpuzzles.sh, run from this
+directory with
+
+    python -m unittest test_romtool
+"""
+
+import json
+import os
+import re
+import subprocess
+import tempfile
+import unittest
+
+from romtool import (BYTE_ORDERS, CONFIG_SECTOR_SIZE, DEFAULT_ROW_TEMPLATE, FLAG_INDEX, FLAG_PACKED_BOARD,
+                     FLAG_PAGE_CHECKSUM, FLAG_PUZZLE_ID, FLAG_RLE, FLAG_ROW_META, FLAG_ROW_TEMPLATE,
+                     FLAG_SECTOR_ALIGN, FORMAT_VERSION, MAX_SECTIONS, ConfigSector, Rom)
+
+FLAGS = (FLAG_PAGE_CHECKSUM, FLAG_INDEX, FLAG_PACKED_BOARD, FLAG_RLE, FLAG_SECTOR_ALIGN,
+         FLAG_ROW_TEMPLATE, FLAG_ROW_META, FLAG_PUZZLE_ID)
+
+
+def config(version, flags, byte_order):
+    """a config sector with every field set to something else than its default"""
+    return ConfigSector(num_pages=300, total_size=300 * 96, num_types=2, font_size=3, version=version,
+                        flags=flags, types=(4, 1, 0, 0), sizes=(96, 128, 0, 0),
+                        sections=[(0, 100), (100, 190), (290, 10)], data_crc32=0xdeadbeef,
+                        type_pages=(290, 10, 0, 0), index_offset=0x8000, index_count=42,
+                        progress_offset=0xffe000, progress_sectors=1, build_time=1700000000,
+                        tool_version='1.0', git_commit=bytes(range(20)), filters_sha256=bytes(range(32)),
+                        row_template=DEFAULT_ROW_TEMPLATE if flags & FLAG_ROW_TEMPLATE else '',
+                        byte_order=byte_order)
+
+
+class ConfigSectorTest(unittest.TestCase):
+
+    def assertRoundTrip(self, sector):
+        data = sector.encode()
+        self.assertEqual(len(data), CONFIG_SECTOR_SIZE)
+        self.assertEqual(vars(ConfigSector.decode(data)), vars(sector))
+
+    def test_every_flag_and_version(self):
+        for byte_order in BYTE_ORDERS:
+            for version in range(FORMAT_VERSION + 1):
+                for flags in (0, sum(FLAGS)) + FLAGS:
+                    with self.subTest(byte_order=byte_order, version=version, flags=flags):
+                        self.assertRoundTrip(config(version, flags, byte_order))
+
+    def test_defaults(self):
+        self.assertRoundTrip(ConfigSector())
+
+    def test_most_sections(self):
+        sector = config(FORMAT_VERSION, 0, 'little')
+        sector.sections = [(i * 10, 10) for i in range(MAX_SECTIONS)]
+        self.assertRoundTrip(sector)
+        sector.sections.append((MAX_SECTIONS * 10, 10))
+        with self.assertRaises(ValueError):
+            sector.encode()
+
+    def test_byte_orders_differ(self):
+        little = config(FORMAT_VERSION, FLAG_INDEX, 'little').encode()
+        big = config(FORMAT_VERSION, FLAG_INDEX, 'big').encode()
+        self.assertNotEqual(little, big)
+        self.assertEqual(ConfigSector.decode(big).byte_order, 'big')
+
+    def test_build(self):
+        sector = ConfigSector.build([(4, 96, 20), (1, 128, 2)], [(0, 20), (20, 2)], 0x1234,
+                                    version=FORMAT_VERSION, flags=FLAG_ROW_TEMPLATE,
+                                    row_template=DEFAULT_ROW_TEMPLATE)
+        self.assertEqual((sector.num_pages, sector.total_size, sector.num_types), (22, 20 * 96 + 2 * 128, 2))
+        self.assertRoundTrip(sector)
+
+
+class SplitSizeTest(unittest.TestCase):
+    """a rom made by fenpuzzles_toflash.sh with --split-size"""
+
+    BANK_SIZE = 16 * 1024
+
+    def setUp(self):
+        directory = tempfile.TemporaryDirectory()
+        self.addCleanup(directory.cleanup)
+        puzzles = os.path.join(directory.name, 'puzzles')
+        os.mkdir(puzzles)
+        # puzzles of 1 to 7 pages, more than the flash takes so every bank has some
+        efen = 'k' + '1' * 62 + 'K'
+        with open(os.path.join(puzzles, 'pages.csv'), 'w') as pages:
+            for i in range(200):
+                total = 1 + i % 7
+                for page in range(1, total + 1):
+                    pages.write('puzzle-p%04d-%d-none-%02d,p%04d,%s,0,1,%d,%d\n' % (
+                        i, 1000 + i, page, i, efen, page, total))
+        self.rom = os.path.join(directory.name, 'out', 'lightnote.rom')
+        subprocess.run(['./fenpuzzles_toflash.sh', '--flash-size', '64K', '--split-size', str(self.BANK_SIZE),
+                        '--index', '--output', self.rom, puzzles],
+                       cwd=os.path.dirname(os.path.abspath(__file__)), check=True, capture_output=True)
+
+    def banks(self):
+        banks = []
+        while os.path.exists('%s.%d' % (self.rom, len(banks))):
+            with open('%s.%d' % (self.rom, len(banks)), 'rb') as bank:
+                banks.append(bank.read())
+        return banks
+
+    def test_banks_make_the_rom(self):
+        banks = self.banks()
+        self.assertEqual(len(banks), 4)
+        self.assertTrue(all(len(bank) == self.BANK_SIZE for bank in banks))
+        with open(self.rom, 'rb') as rom:
+            self.assertEqual(b''.join(banks), rom.read())
+
+    def test_banks_start_on_a_first_page(self):
+        rom = Rom(self.rom)
+        first_pages = {first_page for _, first_page, _, _ in rom.index()}
+        banks = []
+        for page in range(rom.num_pages):
+            if rom.padding(page):
+                continue
+            start = rom.page_offset(page)
+            end = start + rom.config.sizes[rom.page_type(page)] - 1
+            self.assertEqual(start // self.BANK_SIZE, end // self.BANK_SIZE)
+            if not banks or banks[-1] != start // self.BANK_SIZE:
+                self.assertIn(page, first_pages)
+                banks.append(start // self.BANK_SIZE)
+        # every bank has pages
+        self.assertEqual(banks, [0, 1, 2, 3])
+
+class DryRunTest(unittest.TestCase):
+    """genfenpuzzles.sh --dry-run reports the rom a real build makes, with
+    the fenpuzzles_toflash.sh options after --"""
+
+    PUZZLES = """PuzzleId,FEN,Moves,Rating,RatingDeviation,Popularity,NbPlays,Themes,GameUrl,OpeningTags
+00008z0,r6k/pp2r2p/4Rp1Q/3p4/8/1N1P2R1/PqP2bPP/7K b - - 0 24,f2g3 e6e7 b2b1 b3c1 b1c1 h6c1,1913,75,94,6148,\
+crushing hangingPiece long middlegame,https://lichess.org/787zsVup/black#48,
+0000Dz0,5rk1/1p3ppp/pq3b2/8/8/1P1Q1N2/PBP2PPP/3R2K1 w - - 2 27,d3d6 f8d8 d6d8 f6d8,1580,75,98,14385,\
+advantage endgame short,https://lichess.org/F8M8OS71#53,
+"""
+
+    def setUp(self):
+        directory = tempfile.TemporaryDirectory()
+        self.addCleanup(directory.cleanup)
+        self.directory = directory.name
+        self.input = os.path.join(self.directory, 'puzzles.csv')
+        with open(self.input, 'w') as puzzles:
+            puzzles.write(self.PUZZLES)
+
+    def run_script(self, *args):
+        return subprocess.run(args, cwd=os.path.dirname(os.path.abspath(__file__)), check=True,
+                              capture_output=True, text=True).stdout
+
+    def assertSameFreeBytes(self, *toflash_args):
+        output = self.run_script('./genfenpuzzles.sh', '--input', self.input, '--dry-run', '--', *toflash_args)
+        dry_run = int(re.search(r'(\d+) bytes free', output).group(1))
+        # the rom made from the puzzles directory with the same options
+        puzzles = os.path.join(self.directory, 'fenpuzzles')
+        report = os.path.join(self.directory, 'report.json')
+        self.run_script('./genfenpuzzles.sh', '--input', self.input, '--work-dir', puzzles)
+        self.run_script('./fenpuzzles_toflash.sh', *toflash_args, '--output',
+                        os.path.join(self.directory, 'lightnote.rom'), '--report', report, puzzles)
+        with open(report) as real:
+            self.assertEqual(dry_run, json.load(real)['free_bytes'])
+
+    def test_row_size(self):
+        self.assertSameFreeBytes('--row-size', '128')
+
+    def test_packed_board(self):
+        self.assertSameFreeBytes('--packed-board')
+
+
+if __name__ == '__main__':
+    unittest.main()